@@ -0,0 +1,70 @@
+use super::QueryType;
+
+use std::collections::HashSet;
+use std::io::Error;
+use std::io::ErrorKind::Other;
+
+// Limits on the work a single client query may cause
+const MAX_DEPTH: usize = 8;
+const MAX_REFERRALS: usize = 32;
+const MAX_QUERIES: usize = 64;
+
+// Shared by every lookup a client query leads to, including the ones
+// resolving nameserver names, so a misbehaving zone can not keep the
+// resolver busy forever
+pub struct ResolutionBudget {
+    depth: usize,
+    referrals: usize,
+    queries: usize,
+    // Names being resolved further up, asking for one again is a loop
+    // (e.g. ns1.example.com only being reachable through example.com)
+    in_progress: HashSet<(String, QueryType)>,
+}
+
+impl ResolutionBudget {
+    pub fn new() -> ResolutionBudget {
+        ResolutionBudget {
+            depth: 0,
+            referrals: 0,
+            queries: 0,
+            in_progress: HashSet::new(),
+        }
+    }
+
+    // Called when starting to resolve a name, every enter is followed by a leave
+    pub fn enter(&mut self, qname: &str, qtype: QueryType) -> Result<(), Error> {
+        if self.depth >= MAX_DEPTH {
+            return Err(Error::new(Other, format!("Recursion depth exceeded resolving {}", qname)))
+        }
+
+        if !self.in_progress.insert((qname.to_lowercase(), qtype)) {
+            return Err(Error::new(Other, format!("Loop detected resolving {:?} {}", qtype, qname)))
+        }
+
+        self.depth += 1;
+        Ok(())
+    }
+
+    pub fn leave(&mut self, qname: &str, qtype: QueryType) {
+        self.in_progress.remove(&(qname.to_lowercase(), qtype));
+        self.depth -= 1;
+    }
+
+    pub fn spend_query(&mut self) -> Result<(), Error> {
+        if self.queries >= MAX_QUERIES {
+            return Err(Error::new(Other, "Upstream query limit exceeded"))
+        }
+
+        self.queries += 1;
+        Ok(())
+    }
+
+    pub fn spend_referral(&mut self) -> Result<(), Error> {
+        if self.referrals >= MAX_REFERRALS {
+            return Err(Error::new(Other, "Referral limit exceeded"))
+        }
+
+        self.referrals += 1;
+        Ok(())
+    }
+}
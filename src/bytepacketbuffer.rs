@@ -1,17 +1,60 @@
-use std::io::ErrorKind::InvalidInput;
+use std::io::ErrorKind::{InvalidData, InvalidInput, StorageFull};
 use std::io::Error;
+use std::collections::HashMap;
+
+// Plain UDP messages are limited to 512 bytes, TCP messages only by
+// their 2 byte length prefix
+pub const UDP_MAX_SIZE: usize = 512;
+pub const TCP_MAX_SIZE: usize = 65535;
+
+// Names are at most 255 bytes on the wire, and no sane packet needs
+// more than a handful of compression pointers to spell one out
+const MAX_NAME_LEN: usize = 255;
+const MAX_JUMPS: usize = 16;
 
 pub struct BytePacketBuffer {
-    pub buf: [u8; 512],
+    pub buf: Vec<u8>,
     pub pos: usize,
+    max_size: usize,
+    // Offsets of names already written, for compression pointers
+    names: HashMap<String, usize>,
+    // Canonical form (RFC 4034 6.2), as signed by DNSSEC: names are
+    // lowercased and never compressed
+    canonical: bool,
 }
 
+#[allow(unused_parens, clippy::redundant_field_names, clippy::identity_op)]
 impl BytePacketBuffer {
-    // Gives a new buffer for holding a packet
+    // Gives a new zeroed 512 byte buffer for holding a packet
     pub fn new() -> BytePacketBuffer {
         BytePacketBuffer {
-            buf: [0; 512],
+            buf: vec![0; UDP_MAX_SIZE],
+            pos: 0,
+            max_size: UDP_MAX_SIZE,
+            names: HashMap::new(),
+            canonical: false,
+        }
+    }
+
+    // Empty buffer which grows as it is written to, up to max_size bytes
+    pub fn with_max_size(max_size: usize) -> BytePacketBuffer {
+        BytePacketBuffer {
+            buf: Vec::new(),
+            pos: 0,
+            max_size: max_size,
+            names: HashMap::new(),
+            canonical: false,
+        }
+    }
+
+    // Buffer over a received message, reads stop at the end of the data
+    pub fn from_bytes(data: &[u8]) -> BytePacketBuffer {
+        BytePacketBuffer {
+            buf: data.to_vec(),
             pos: 0,
+            max_size: data.len(),
+            names: HashMap::new(),
+            canonical: false,
         }
     }
 
@@ -20,13 +63,39 @@ impl BytePacketBuffer {
         self.pos
     }
 
-    pub fn step(&mut self, steps: usize) -> Result<(), ()> {
-        self.pos += steps;
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    // Switches to the canonical form, for writing data to verify signatures over
+    pub fn set_canonical(&mut self, canonical: bool) {
+        self.canonical = canonical;
+    }
+
+    // Lets a writer hold back room for something that has to come last
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+    }
+
+    // Drops everything written from pos onwards, including names which
+    // could otherwise be pointed at by later compression pointers
+    pub fn truncate(&mut self, pos: usize) -> Result<(), Error> {
+        self.seek(pos)?;
+        self.buf.truncate(pos);
+        self.names.retain(|_, offset| *offset < pos);
 
         Ok(())
     }
 
-    pub fn seek(&mut self, pos: usize) -> Result<(), ()> {
+    pub fn step(&mut self, steps: usize) -> Result<(), (Error)> {
+        self.seek(self.pos + steps)
+    }
+
+    // Moving to the very end is allowed, anything past it is not
+    pub fn seek(&mut self, pos: usize) -> Result<(), (Error)> {
+        if pos > self.buf.len() {
+            return Err(Error::new(InvalidInput, "End of buffer"))
+        }
         self.pos = pos;
 
         Ok(())
@@ -34,7 +103,7 @@ impl BytePacketBuffer {
 
     // Reads single byte and progresses by one step
     pub fn read(&mut self) -> Result<(u8), (Error)> {
-        if self.pos >= 512 {
+        if self.pos >= self.buf.len() {
             return Err(Error::new(InvalidInput, "End of buffer"))
         }
 
@@ -46,17 +115,17 @@ impl BytePacketBuffer {
 
     // Gets data without changing self position
     pub fn get(&mut self, pos: usize) -> Result<(u8), (Error)> {
-        if pos >= 512 {
+        if pos >= self.buf.len() {
             return Err(Error::new(InvalidInput, "End of buffer"))
         }
         Ok(self.buf[pos])
     }
 
     pub fn get_range(&mut self, start: usize, len: usize) -> Result<(&[u8]), (Error)> {
-        if start + len >= 512 {
+        if start + len > self.buf.len() {
             return Err(Error::new(InvalidInput, "End of buffer"))
         }
-        Ok(&self.buf[start..start+len as usize])
+        Ok(&self.buf[start..start+len])
     }
 
     // Reads u16/u32 from buffer, stepping forward 2/4 bytes
@@ -77,6 +146,9 @@ impl BytePacketBuffer {
         // Tracking position locally, so when can make jumps
         let mut pos = self.pos();
         let mut jumped = false;
+        let mut jumps = 0;
+        // Length of the name as it would be written out, labels + root
+        let mut name_len = 1;
 
         let mut delim = "";
         loop {
@@ -84,18 +156,31 @@ impl BytePacketBuffer {
 
             // If 2 MSB are set, indicates a jump
             if (len & 0xC0) == 0xC0 {
-                // Set buffer position past label position
-                if !jumped {
-                    let _ = self.seek(pos+2);
+                // Crafted packets could otherwise keep us jumping around forever
+                jumps += 1;
+                if jumps > MAX_JUMPS {
+                    return Err(Error::new(InvalidData, "Too many compression pointers in name"))
                 }
 
                 // Read, calculate offset and jump
                 let b2 = self.get(pos+1)? as u16;
-                let offset = (((len as u16) ^ 0xC0) << 8) | b2;
-                pos = offset as usize;
+                let offset = ((((len as u16) ^ 0xC0) << 8) | b2) as usize;
+
+                // Only earlier data may be pointed to (RFC 1035 4.1.4)
+                if offset >= pos {
+                    return Err(Error::new(InvalidData, "Compression pointer does not point backwards"))
+                }
+
+                // Set buffer position past label position
+                if !jumped {
+                    self.seek(pos+2)?;
+                }
+                pos = offset;
 
                 // We now have position of label and jumped
                 jumped = true;
+            } else if (len & 0xC0) != 0 {
+                return Err(Error::new(InvalidData, "Unsupported label type in name"))
             } else {
                 // move byte forward, past the length byte
                 pos += 1;
@@ -105,12 +190,17 @@ impl BytePacketBuffer {
                     break;
                 }
 
+                name_len += len as usize + 1;
+                if name_len > MAX_NAME_LEN {
+                    return Err(Error::new(InvalidData, "Name exceeds the 255 byte length"))
+                }
+
                 // Appends delimiter to the output buffer first
                 outstr.push_str(delim);
                 // Decode ASCII bytes from label and append to buffer
                 let str_buffer = self.get_range(pos, len as usize)?;
-                outstr.push_str(&String::from_utf8_lossy(str_buffer)
-                    .to_lowercase());
+                // Case is kept as sent, names are only compared ignoring it
+                outstr.push_str(&String::from_utf8_lossy(str_buffer));
 
                 // Changes delim; based on the way packets are decoded
                 delim = ".";
@@ -121,14 +211,21 @@ impl BytePacketBuffer {
 
         // If jumped already been performed, do not do it again
         if !jumped {
-            let _ = self.seek(pos);
+            self.seek(pos)?;
         }
         Ok(())
     }
 
+    // Running out of room has an error kind of its own, so a packet can
+    // tell it apart from data which can not be written at all
     pub fn write(&mut self, val: u8) -> Result<(), (Error)> {
-        if self.pos >= 512 {
-            return Err(Error::new(InvalidInput, "End of buffer"))
+        if self.pos >= self.max_size {
+            return Err(Error::new(StorageFull, "Buffer is full"))
+        }
+
+        // Grows the buffer when writing past what is already there
+        if self.pos >= self.buf.len() {
+            self.buf.resize(self.pos + 1, 0);
         }
         self.buf[self.pos] = val;
         self.pos += 1;
@@ -156,33 +253,200 @@ impl BytePacketBuffer {
         Ok(())
     }
 
-    pub fn write_qname(&mut self, qname: &str) -> Result<(), (Error)> {
-        let split_str = qname.split(".").collect::<Vec<&str>>();
-
-        for label in split_str {
-            let len = label.len();
-            if len > 0x34 {
+    // Splits a name into its labels, checking each fits in a length byte
+    fn split_qname(qname: &str) -> Result<Vec<&str>, Error> {
+        // The root ("" or ".") is just the terminating zero, e.g. SOA owners
+        // of the root zone, and a trailing dot is not a label of its own
+        let qname = qname.trim_end_matches('.');
+        let split_str = qname.split(".")
+            .filter(|label| !label.is_empty())
+            .collect::<Vec<&str>>();
+
+        for label in &split_str {
+            if label.len() > 0x3F {
                 return Err(Error::new(InvalidInput, "Single label exceeds the 63 character length"))
             }
+        }
 
-            self.write_u8(len as u8)?;
-            for b in label.as_bytes() {
+        Ok(split_str)
+    }
+
+    fn write_label(&mut self, label: &str) -> Result<(), Error> {
+        self.write_u8(label.len() as u8)?;
+        for b in label.as_bytes() {
+            if self.canonical {
+                self.write_u8(b.to_ascii_lowercase())?;
+            } else {
                 self.write_u8(*b)?;
             }
         }
+
+        Ok(())
+    }
+
+    // Writes a name, replacing the longest suffix already written
+    // elsewhere in the packet with a pointer to it
+    pub fn write_qname(&mut self, qname: &str) -> Result<(), (Error)> {
+        if self.canonical {
+            return self.write_qname_uncompressed(qname); }
+
+        let split_str = BytePacketBuffer::split_qname(qname)?;
+
+        for i in 0..split_str.len() {
+            let suffix = split_str[i..].join(".").to_lowercase();
+            if let Some(&offset) = self.names.get(&suffix) {
+                self.write_u16(0xC000 | offset as u16)?;
+                return Ok(());
+            }
+
+            // Pointers only have 14 bits for the offset
+            if self.pos < 0x4000 {
+                self.names.insert(suffix, self.pos);
+            }
+            self.write_label(split_str[i])?;
+        }
+        self.write_u8(0)?;
+        Ok(())
+    }
+
+    // Names in rdata of types a resolver might not know (e.g. the SRV
+    // target) must be written in full (RFC 3597, RFC 2782)
+    pub fn write_qname_uncompressed(&mut self, qname: &str) -> Result<(), Error> {
+        for label in BytePacketBuffer::split_qname(qname)? {
+            self.write_label(label)?;
+        }
         self.write_u8(0)?;
         Ok(())
     }
 
-    pub fn set(&mut self, pos: usize, val: u8) -> Result<(), ()> {
+    // Names which keep their case even in the canonical form, i.e. the
+    // NSEC next domain name (RFC 6840 5.1)
+    pub fn write_qname_preserving_case(&mut self, qname: &str) -> Result<(), Error> {
+        let canonical = self.canonical;
+        self.canonical = false;
+        let result = self.write_qname_uncompressed(qname);
+        self.canonical = canonical;
+        result
+    }
+
+    pub fn set(&mut self, pos: usize, val: u8) -> Result<(), (Error)> {
+        if pos >= self.buf.len() {
+            return Err(Error::new(InvalidInput, "End of buffer"))
+        }
         self.buf[pos] = val;
         Ok(())
     }
 
-    pub fn set_u16(&mut self, pos: usize, val: u16) -> Result<(), ()> {
+    pub fn set_u16(&mut self, pos: usize, val: u16) -> Result<(), (Error)> {
         self.set(pos, (val >> 8) as u8)?;
         self.set(pos + 1, (val & 0xFF) as u8)?;
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_name(data: &[u8], pos: usize) -> Result<String, Error> {
+        let mut buffer = BytePacketBuffer::from_bytes(data);
+        buffer.seek(pos)?;
+        let mut name = String::new();
+        buffer.read_qname(&mut name)?;
+        Ok(name)
+    }
+
+    #[test]
+    fn names_follow_pointers_backwards() {
+        // "example.com" at 0, then "www" pointing back at it
+        let data = b"\x07example\x03com\x00\x03www\xC0\x00";
+        assert_eq!(read_name(data, 13).unwrap(), "www.example.com");
+    }
+
+    #[test]
+    fn pointers_to_themselves_are_rejected() {
+        let data = b"\x03www\xC0\x04";
+        assert!(read_name(data, 0).is_err());
+    }
+
+    #[test]
+    fn pointers_forward_are_rejected() {
+        let data = b"\xC0\x02\x03com\x00";
+        assert!(read_name(data, 0).is_err());
+    }
+
+    #[test]
+    fn too_many_pointers_are_rejected() {
+        // The root, then pointers which each lead to the one before it
+        let mut data = vec![0];
+        for i in 0..MAX_JUMPS + 1 {
+            let target = if i == 0 { 0 } else { data.len() - 2 };
+            data.extend_from_slice(&[0xC0, target as u8]);
+        }
+        let last = data.len() - 2;
+        assert!(read_name(&data, last).is_err());
+
+        // One jump less is fine
+        assert_eq!(read_name(&data, last - 2).unwrap(), "");
+    }
+
+    #[test]
+    fn names_over_255_bytes_are_rejected() {
+        // Four 63 byte labels make 257 bytes with their lengths and the root
+        let mut data = Vec::new();
+        for _ in 0..4 {
+            data.push(63);
+            data.extend_from_slice(&[b'a'; 63]);
+        }
+        data.push(0);
+        assert!(read_name(&data, 0).is_err());
+
+        // Three of them and a 61 byte label make exactly 255
+        data[3 * 64] = 61;
+        data.drain(3 * 64 + 62..4 * 64);
+        assert_eq!(read_name(&data, 0).unwrap().len(), 3 * 64 + 61);
+    }
+
+    #[test]
+    fn moving_past_the_end_is_rejected() {
+        let mut buffer = BytePacketBuffer::from_bytes(&[1, 2, 3]);
+        assert!(buffer.seek(4).is_err());
+        assert!(buffer.seek(3).is_ok());
+        assert!(buffer.read().is_err());
+
+        buffer.seek(1).unwrap();
+        assert!(buffer.step(3).is_err());
+        assert!(buffer.step(2).is_ok());
+
+        assert!(buffer.set(3, 0).is_err());
+        assert!(buffer.set_u16(2, 0).is_err());
+        assert!(buffer.set_u16(1, 0).is_ok());
+    }
+
+    #[test]
+    fn repeated_suffixes_become_pointers() {
+        let mut buffer = BytePacketBuffer::with_max_size(512);
+        buffer.write_qname("www.example.com").unwrap();
+        buffer.write_qname("mail.EXAMPLE.com").unwrap();
+        buffer.write_qname("www.example.com").unwrap();
+
+        assert_eq!(&buffer.buf[17..], b"\x04mail\xC0\x04\xC0\x00");
+        assert_eq!(read_name(&buffer.buf, 17).unwrap(), "mail.example.com");
+        assert_eq!(read_name(&buffer.buf, 24).unwrap(), "www.example.com");
+    }
+
+    #[test]
+    fn truncated_names_are_not_pointed_to() {
+        let mut buffer = BytePacketBuffer::with_max_size(512);
+        buffer.write_qname("example.com").unwrap();
+        let pos = buffer.pos();
+        buffer.write_qname("www.example.org").unwrap();
+        buffer.truncate(pos).unwrap();
+
+        // Written out in full again, only the name before is pointed to
+        buffer.write_qname("mail.example.org").unwrap();
+        buffer.write_qname("www.example.com").unwrap();
+        assert_eq!(&buffer.buf[pos..], b"\x04mail\x07example\x03org\x00\x03www\xC0\x00");
+        assert_eq!(read_name(&buffer.buf, pos).unwrap(), "mail.example.org");
+    }
+}
@@ -0,0 +1,486 @@
+use super::{
+    DnsPacket,
+    DnsRecord,
+    QueryType,
+    ResultCode,
+    };
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use rand::thread_rng;
+use rand::seq::SliceRandom;
+
+// Only the IN class is handled by the resolver
+pub const CLASS_IN: u16 = 1;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub name: String,
+    pub qtype: QueryType,
+    pub class: u16,
+}
+
+#[allow(clippy::redundant_field_names)]
+impl CacheKey {
+    pub fn new(name: &str, qtype: QueryType, class: u16) -> CacheKey {
+        CacheKey {
+            name: name.to_lowercase(),
+            qtype: qtype,
+            class: class,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct CacheEntry {
+    record: DnsRecord,
+    // Whether DNSSEC validation found the record secure
+    secure: bool,
+    expires: Instant,
+}
+
+// Remembers that a name does not exist (NXDOMAIN) or has no records of
+// a type (NODATA), along with the SOA which proves it (RFC 2308) and the
+// NSEC or NSEC3 records and signatures which do so for DNSSEC
+#[derive(Clone, Debug)]
+struct NegativeEntry {
+    rescode: ResultCode,
+    soa: DnsRecord,
+    proof: Vec<DnsRecord>,
+    secure: bool,
+    expires: Instant,
+}
+
+type RecordSets = HashMap<CacheKey, Vec<CacheEntry>>;
+
+// Answer cache shared between lookups, each key holds a full record set
+// which is replaced as a whole whenever a fresher copy is seen
+pub struct Cache {
+    entries: RwLock<RecordSets>,
+    // NS and glue records from referrals and the other sections, which
+    // only point lookups at servers and are never handed out as answers
+    delegations: RwLock<RecordSets>,
+    // NXDOMAIN covers every type of a name, so it is keyed by name alone
+    nxdomain: RwLock<HashMap<String, NegativeEntry>>,
+    nodata: RwLock<HashMap<CacheKey, NegativeEntry>>,
+}
+
+#[allow(clippy::redundant_field_names)]
+impl Cache {
+    pub fn new() -> Cache {
+        Cache {
+            entries: RwLock::new(HashMap::new()),
+            delegations: RwLock::new(HashMap::new()),
+            nxdomain: RwLock::new(HashMap::new()),
+            nodata: RwLock::new(HashMap::new()),
+        }
+    }
+
+    // Returns the cached record set with TTLs counted down to what is left,
+    // nothing is returned once any record in the set has expired
+    pub fn lookup(&self, qname: &str, qtype: QueryType) -> Option<Vec<DnsRecord>> {
+        self.lookup_set(qname, qtype).map(|(records, _)| records)
+    }
+
+    // Same as lookup, along with whether the whole set is secure
+    fn lookup_set(&self, qname: &str, qtype: QueryType) -> Option<(Vec<DnsRecord>, bool)> {
+        Cache::lookup_in(&self.entries, &CacheKey::new(qname, qtype, CLASS_IN))
+    }
+
+    fn lookup_in(sets: &RwLock<RecordSets>, key: &CacheKey) -> Option<(Vec<DnsRecord>, bool)> {
+        let sets = sets.read().unwrap();
+        let set = sets.get(key)?;
+
+        let now = Instant::now();
+        let mut records = Vec::new();
+        let mut secure = true;
+        for entry in set {
+            if entry.expires <= now {
+                return None; }
+
+            let mut rec = entry.record.clone();
+            rec.set_ttl(entry.expires.duration_since(now).as_secs() as u32);
+            records.push(rec);
+            secure = secure && entry.secure;
+        }
+
+        if records.is_empty() {
+            return None; }
+
+        Some((records, secure))
+    }
+
+    // Returns the rescode and authority records (the SOA first) of a cached
+    // negative answer, with TTLs counted down so clients do not cache them
+    // for longer than we do, and whether it is secure
+    pub fn lookup_negative(&self, qname: &str, qtype: QueryType) -> Option<(ResultCode, Vec<DnsRecord>, bool)> {
+        let now = Instant::now();
+        let name = qname.to_lowercase();
+
+        let entry = match self.nxdomain.read().unwrap().get(&name) {
+            Some(entry) if entry.expires > now => Some(entry.clone()),
+            _ => None,
+        };
+        let entry = match entry {
+            Some(entry) => entry,
+            None => {
+                let key = CacheKey::new(qname, qtype, CLASS_IN);
+                match self.nodata.read().unwrap().get(&key) {
+                    Some(entry) if entry.expires > now => entry.clone(),
+                    _ => return None,
+                }
+            }
+        };
+
+        let ttl = entry.expires.duration_since(now).as_secs() as u32;
+        let mut records = vec![entry.soa];
+        records.extend(entry.proof);
+        for rec in &mut records {
+            rec.set_ttl(ttl); }
+        Some((entry.rescode, records, entry.secure))
+    }
+
+    // Builds a response from the cache, as if an upstream server answered
+    pub fn lookup_packet(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        let mut packet = DnsPacket::new();
+
+        if let Some((answers, secure)) = self.lookup_set(qname, qtype) {
+            packet.answers = answers;
+            packet.header.authed_data = secure;
+            return Some(packet); }
+
+        // An alias is just as good, the resolver follows it from there
+        if qtype != QueryType::CNAME {
+            if let Some((answers, secure)) = self.lookup_set(qname, QueryType::CNAME) {
+                packet.answers = answers;
+                packet.header.authed_data = secure;
+                return Some(packet); }
+
+            // So is a DNAME further up, which redirects the whole subtree
+            let mut zone = qname.to_lowercase();
+            while let Some(idx) = zone.find('.') {
+                zone = zone[idx+1..].to_string();
+                if let Some((answers, secure)) = self.lookup_set(&zone, QueryType::DNAME) {
+                    packet.answers = answers;
+                    packet.header.authed_data = secure;
+                    return Some(packet); }
+            }
+        }
+
+        let (rescode, authorities, secure) = self.lookup_negative(qname, qtype)?;
+        packet.header.rescode = rescode;
+        packet.header.authed_data = secure;
+        packet.authorities = authorities;
+        Some(packet)
+    }
+
+    pub fn store(&self, records: &[DnsRecord], secure: bool) {
+        Cache::store_in(&self.entries, records, secure);
+    }
+
+    fn store_in(sets: &RwLock<RecordSets>, records: &[DnsRecord], secure: bool) {
+        // Group into record sets first so a set is never partially replaced
+        let mut new_sets: RecordSets = HashMap::new();
+        let now = Instant::now();
+        for rec in records {
+            let ttl = rec.get_ttl();
+            if ttl == 0 {
+                continue; }
+
+            // Signatures are kept with the set they cover, so they are
+            // handed out along with it
+            let qtype = match *rec {
+                DnsRecord::RRSIG { type_covered, .. } => type_covered,
+                _ => rec.get_querytype(),
+            };
+            let key = CacheKey::new(rec.get_domain(), qtype, rec.get_class());
+            new_sets.entry(key).or_default().push(CacheEntry {
+                record: rec.clone(),
+                secure: secure,
+                expires: now + Duration::from_secs(ttl as u64),
+            });
+        }
+
+        let mut sets = sets.write().unwrap();
+        sets.retain(|_, set| set.iter().all(|entry| entry.expires > now));
+        for (key, mut set) in new_sets {
+            set.sort_by(|a, b| a.record.cmp(&b.record));
+            set.dedup_by(|a, b| a.record == b.record);
+            sets.insert(key, set);
+        }
+    }
+
+    // Only the answers are covered by validation, referrals and glue are
+    // never signed and anyone on the path could have added them, so they
+    // are kept apart for finding nameservers
+    pub fn store_packet(&self, packet: &DnsPacket) {
+        self.store(&packet.answers, packet.header.authed_data);
+        Cache::store_in(&self.delegations, &packet.authorities, false);
+        Cache::store_in(&self.delegations, &packet.resources, false);
+    }
+
+    // Delegation data, falling back to answers such as an NS lookup gave
+    fn lookup_delegation(&self, qname: &str, qtype: QueryType) -> Option<Vec<DnsRecord>> {
+        Cache::lookup_in(&self.delegations, &CacheKey::new(qname, qtype, CLASS_IN))
+            .map(|(records, _)| records)
+            .or_else(|| self.lookup(qname, qtype))
+    }
+
+    // Caches an NXDOMAIN or NODATA response, which is only possible when
+    // the authority section carries the zone's SOA
+    pub fn store_negative(&self, qname: &str, qtype: QueryType, packet: &DnsPacket) {
+        let soa = packet.authorities.iter()
+            .find(|rec| rec.get_querytype() == QueryType::SOA);
+
+        let soa = match soa {
+            Some(x) => x.clone(),
+            None => return,
+        };
+
+        // Negative TTL is the lesser of the SOA's own TTL and its minimum field
+        let ttl = match soa {
+            DnsRecord::SOA { minimum, ttl, .. } => std::cmp::min(minimum, ttl),
+            _ => return,
+        };
+        if ttl == 0 {
+            return; }
+
+        let proof = packet.authorities.iter()
+            .filter(|rec| matches!(rec.get_querytype(), QueryType::RRSIG | QueryType::NSEC | QueryType::NSEC3))
+            .cloned()
+            .collect();
+
+        let now = Instant::now();
+        let entry = NegativeEntry {
+            rescode: packet.header.rescode,
+            soa: soa,
+            proof: proof,
+            secure: packet.header.authed_data,
+            expires: now + Duration::from_secs(ttl as u64),
+        };
+
+        // Names asked for at random would otherwise pile up forever
+        if packet.header.rescode == ResultCode::NXDOMAIN {
+            let mut nxdomain = self.nxdomain.write().unwrap();
+            nxdomain.retain(|_, entry| entry.expires > now);
+            nxdomain.insert(qname.to_lowercase(), entry);
+        } else {
+            let key = CacheKey::new(qname, qtype, CLASS_IN);
+            let mut nodata = self.nodata.write().unwrap();
+            nodata.retain(|_, entry| entry.expires > now);
+            nodata.insert(key, entry);
+        }
+    }
+
+    // Finds the deepest zone we hold a delegation for, returning it with
+    // the addresses of its nameservers so a lookup can skip the root
+    pub fn get_closest_ns(&self, qname: &str) -> Option<(String, Vec<String>)> {
+        let mut zone = qname.to_lowercase();
+
+        while !zone.is_empty() {
+            if let Some(ns_records) = self.lookup_delegation(&zone, QueryType::NS) {
+                let mut addrs = Vec::new();
+                for ns in &ns_records {
+                    if let DnsRecord::NS { ref host, .. } = *ns {
+                        if let Some(a_records) = self.lookup_delegation(host, QueryType::A) {
+                            for a in a_records {
+                                if let DnsRecord::A { addr, .. } = a {
+                                    addrs.push(addr.to_string()); }}}}}
+
+                if !addrs.is_empty() {
+                    addrs.shuffle(&mut thread_rng());
+                    return Some((zone, addrs)); }
+            }
+
+            // Moves up a level, e.g. www.google.com -> google.com
+            zone = match zone.find('.') {
+                Some(idx) => zone[idx+1..].to_string(),
+                None => String::new(),
+            };
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_packet::tests::rrsig;
+
+    #[test]
+    fn unknown_record_keeps_its_class() {
+        let record = DnsRecord::UNKNOWN {
+            domain: "a".to_string(),
+            qtype: 99,
+            class: 3,
+            data: vec![1, 2, 3],
+            ttl: 3600,
+        };
+        let cache = Cache::new();
+        cache.store(&[record], false);
+
+        // Questions are always IN, so it is never handed out as an answer
+        assert!(cache.lookup("a", QueryType::UNKNOWN(99)).is_none());
+
+        let key = CacheKey::new("a", QueryType::UNKNOWN(99), 3);
+        let (records, _) = Cache::lookup_in(&cache.entries, &key).unwrap();
+        assert_eq!(records.len(), 1);
+        match records[0] {
+            DnsRecord::UNKNOWN { class, ref data, .. } => {
+                assert_eq!(class, 3);
+                assert_eq!(data, &vec![1, 2, 3]);
+            },
+            ref other => panic!("Unexpected record {:?}", other),
+        }
+    }
+
+    #[allow(clippy::redundant_field_names)]
+    fn a_record(domain: &str, ttl: u32) -> DnsRecord {
+        DnsRecord::A {
+            domain: domain.to_string(),
+            addr: "192.0.2.1".parse().unwrap(),
+            ttl: ttl,
+        }
+    }
+
+    #[test]
+    fn ttls_count_down() {
+        let cache = Cache::new();
+        cache.store(&[a_record("www.example.com", 300)], false);
+        std::thread::sleep(Duration::from_millis(1100));
+
+        let records = cache.lookup("WWW.example.com", QueryType::A).unwrap();
+        assert!(records[0].get_ttl() < 300);
+        assert!(records[0].get_ttl() >= 298);
+    }
+
+    #[test]
+    fn expired_sets_are_not_returned() {
+        let cache = Cache::new();
+        cache.store(&[a_record("a.example.com", 1), a_record("b.example.com", 300)], false);
+        // Nothing is kept for records which may not be cached at all
+        cache.store(&[a_record("c.example.com", 0)], false);
+        std::thread::sleep(Duration::from_millis(1100));
+
+        assert!(cache.lookup("a.example.com", QueryType::A).is_none());
+        assert!(cache.lookup("b.example.com", QueryType::A).is_some());
+        assert!(cache.lookup("c.example.com", QueryType::A).is_none());
+
+        // Storing prunes what has expired
+        cache.store(&[a_record("d.example.com", 300)], false);
+        assert_eq!(cache.entries.read().unwrap().len(), 2);
+    }
+
+    fn referral() -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.authorities.push(DnsRecord::NS {
+            domain: "example.com".to_string(),
+            host: "ns.example.com".to_string(),
+            ttl: 3600,
+        });
+        packet.resources.push(DnsRecord::A {
+            domain: "ns.example.com".to_string(),
+            addr: "192.0.2.1".parse().unwrap(),
+            ttl: 3600,
+        });
+        packet
+    }
+
+    #[test]
+    fn referrals_are_not_answers() {
+        let cache = Cache::new();
+        cache.store_packet(&referral());
+
+        assert!(cache.lookup_packet("ns.example.com", QueryType::A).is_none());
+        assert!(cache.lookup_packet("example.com", QueryType::NS).is_none());
+    }
+
+    #[test]
+    fn referrals_lead_to_their_servers() {
+        let cache = Cache::new();
+        cache.store_packet(&referral());
+
+        let (zone, servers) = cache.get_closest_ns("www.example.com").unwrap();
+        assert_eq!(zone, "example.com");
+        assert_eq!(servers, vec!["192.0.2.1".to_string()]);
+    }
+
+    #[test]
+    fn signatures_are_cached_with_their_set() {
+        let mut packet = DnsPacket::new();
+        packet.header.authed_data = true;
+        packet.answers = vec![
+            DnsRecord::A {
+                domain: "www.example.com".to_string(),
+                addr: "192.0.2.1".parse().unwrap(),
+                ttl: 300,
+            },
+            rrsig("www.example.com", QueryType::A),
+            rrsig("www.example.com", QueryType::AAAA),
+        ];
+        let cache = Cache::new();
+        cache.store_packet(&packet);
+
+        let cached = cache.lookup_packet("www.example.com", QueryType::A).unwrap();
+        assert!(cached.header.authed_data);
+        assert_eq!(cached.answers.len(), 2);
+        assert_eq!(cached.get_signatures_for("www.example.com", QueryType::A).len(), 1);
+    }
+
+    #[allow(clippy::redundant_field_names)]
+    fn negative(rescode: ResultCode, minimum: u32) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.rescode = rescode;
+        packet.authorities.push(DnsRecord::SOA {
+            domain: "example.com".to_string(),
+            mname: "ns.example.com".to_string(),
+            rname: "admin.example.com".to_string(),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: minimum,
+            ttl: 3600,
+        });
+        packet
+    }
+
+    #[test]
+    fn negative_answers_keep_their_proof() {
+        let mut packet = negative(ResultCode::NXDOMAIN, 300);
+        packet.header.authed_data = true;
+        packet.authorities.extend(vec![
+            rrsig("example.com", QueryType::SOA),
+            DnsRecord::NSEC {
+                domain: "example.com".to_string(),
+                next_domain: "www.example.com".to_string(),
+                types: vec![QueryType::SOA, QueryType::RRSIG, QueryType::NSEC],
+                ttl: 300,
+            },
+            rrsig("example.com", QueryType::NSEC),
+        ]);
+        let cache = Cache::new();
+        cache.store_negative("nope.example.com", QueryType::A, &packet);
+
+        let cached = cache.lookup_packet("nope.example.com", QueryType::A).unwrap();
+        assert_eq!(cached.header.rescode, ResultCode::NXDOMAIN);
+        assert!(cached.header.authed_data);
+        assert_eq!(cached.authorities.len(), 4);
+        assert_eq!(cached.authorities[0].get_querytype(), QueryType::SOA);
+    }
+
+    #[test]
+    fn expired_negative_answers_are_dropped() {
+        let cache = Cache::new();
+        cache.store_negative("a.example.com", QueryType::A, &negative(ResultCode::NXDOMAIN, 1));
+        cache.store_negative("a.example.com", QueryType::A, &negative(ResultCode::NOERROR, 1));
+        std::thread::sleep(Duration::from_millis(1100));
+
+        cache.store_negative("b.example.com", QueryType::A, &negative(ResultCode::NXDOMAIN, 1));
+        cache.store_negative("b.example.com", QueryType::A, &negative(ResultCode::NOERROR, 1));
+        assert_eq!(cache.nxdomain.read().unwrap().len(), 1);
+        assert_eq!(cache.nodata.read().unwrap().len(), 1);
+    }
+}
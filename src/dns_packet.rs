@@ -5,13 +5,32 @@ use super::{
     DnsQuestion,
     DnsHeader,
     QueryType,
+    EdnsOpt,
+    ResultCode,
     };
 
+use std::collections::BTreeMap;
 use std::io::Error;
+use std::io::ErrorKind::StorageFull;
 use rand::random;
+use rand::thread_rng;
+use rand::seq::SliceRandom;
 
 
 
+// Whether name is zone itself or lies below it, compared label by label
+// so that notexample.com is not taken to be inside example.com
+pub fn in_zone(name: &str, zone: &str) -> bool {
+    // DNS only folds the case of ASCII letters (RFC 4343)
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    let zone = zone.trim_end_matches('.').to_ascii_lowercase();
+
+    if zone.is_empty() || name == zone {
+        return true; }
+
+    name.ends_with(&format!(".{}", zone))
+}
+
 #[derive(Clone, Debug)]
 pub struct DnsPacket {
     pub header: DnsHeader,
@@ -19,8 +38,11 @@ pub struct DnsPacket {
     pub answers: Vec<DnsRecord>,
     pub authorities: Vec<DnsRecord>,
     pub resources: Vec<DnsRecord>,
+    // OPT pseudo-record from the additional section, if EDNS is in use
+    pub edns: Option<EdnsOpt>,
 }
 
+#[allow(unused_parens)]
 impl DnsPacket {
     pub fn new() -> DnsPacket {
         DnsPacket {
@@ -28,7 +50,8 @@ impl DnsPacket {
             questions: Vec::new(),
             answers: Vec::new(),
             authorities: Vec::new(),
-            resources: Vec::new()
+            resources: Vec::new(),
+            edns: None,
         }
     }
 
@@ -53,44 +76,202 @@ impl DnsPacket {
         }
 
         for _ in 0..result.header.resource_entries {
+            if result.edns.is_none() && EdnsOpt::is_next(buffer)? {
+                result.edns = Some(EdnsOpt::read(buffer)?);
+                continue; }
+
             let rec = DnsRecord::read(buffer)?;
             result.resources.push(rec);
         }
 
+        // With EDNS the rcode is 12 bits, the upper 8 are in the OPT record
+        if let Some(ref edns) = result.edns {
+            let rcode = ((edns.extended_rcode as u16) << 4) | result.header.rescode.to_num();
+            result.header.rescode = ResultCode::from_num(rcode);
+        }
+
         Ok(result)
     }
 
+    // Writes as many whole records as fit in the buffer, dropping them
+    // from the end of the additional, authority and then answer section.
+    // The header is rewritten at the end to match what was actually written
     pub fn write(&mut self, buffer: &mut BytePacketBuffer) -> Result<(), (Error)> {
         self.header.questions = self.questions.len() as u16;
         self.header.answers = self.answers.len() as u16;
         self.header.authoritative_entries = self.authorities.len() as u16;
-        self.header.resource_entries = self.resources.len() as u16;
+        self.header.resource_entries = self.resources.len() as u16 +
+            self.edns.is_some() as u16;
 
+        if let Some(ref mut edns) = self.edns {
+            edns.extended_rcode = (self.header.rescode.to_num() >> 4) as u8;
+        } else if self.header.rescode.to_num() > 0x0F {
+            // Without OPT the upper bits are lost and the low 4 would read
+            // as some other rcode, so the client is told the lookup failed
+            self.header.rescode = ResultCode::SERVFAIL;
+        }
+
+        let start_pos = buffer.pos();
         self.header.write(buffer)?;
 
         for question in &mut self.questions {
             question.write(buffer)?;
         }
 
-        for rec in &mut self.answers {
-            rec.write(buffer)?;
-        }
+        // Room is kept for the OPT record, which has to survive truncation
+        let max_size = buffer.max_size();
+        let edns_len = self.edns.as_ref().map_or(0, |edns| edns.len());
+        buffer.set_max_size(max_size.saturating_sub(edns_len));
 
-        for rec in &mut self.authorities {
-            rec.write(buffer)?;
+        let mut counts = [0u16; 3];
+        let mut full = false;
+        let sections = [&self.answers, &self.authorities, &self.resources];
+        for (section, count) in sections.iter().zip(counts.iter_mut()) {
+            for rec in section.iter() {
+                let pos = buffer.pos();
+                match rec.write(buffer) {
+                    Ok(_) => {},
+                    // Only running out of room truncates, a record which can
+                    // not be written at all is an error of its own
+                    Err(ref e) if e.kind() == StorageFull => {
+                        buffer.truncate(pos)?;
+                        full = true;
+                        break;
+                    },
+                    Err(e) => return Err(e),
+                }
+
+                *count += 1;
+            }
+
+            if full {
+                break; }
         }
 
-        for rec in &mut self.resources {
-            rec.write(buffer)?;
+        buffer.set_max_size(max_size);
+        if let Some(ref edns) = self.edns {
+            edns.write(buffer)?;
         }
 
+        // Dropping additional records alone does not call for TC, the
+        // answer is still complete without them (RFC 2181 9)
+        self.header.truncated_message = counts[0] < self.answers.len() as u16 ||
+            counts[1] < self.authorities.len() as u16;
+        self.header.answers = counts[0];
+        self.header.authoritative_entries = counts[1];
+        self.header.resource_entries = counts[2] + self.edns.is_some() as u16;
+
+        let end_pos = buffer.pos();
+        buffer.seek(start_pos)?;
+        self.header.write(buffer)?;
+        buffer.seek(end_pos)?;
+
         Ok(())
     }
 
+    // Checks that a response really answers our query, a spoofed one has
+    // to guess the ID and echo the question exactly
+    pub fn is_response_to(&self, query: &DnsPacket) -> bool {
+        if !self.header.response || self.header.id != query.header.id {
+            return false; }
+
+        if self.questions.len() != query.questions.len() {
+            return false; }
+
+        self.questions.iter().zip(query.questions.iter()).all(|(a, b)| {
+            a.qtype == b.qtype && a.name.eq_ignore_ascii_case(&b.name)
+        })
+    }
+
+    // Drops every record a server for zone has no authority over, so a
+    // response can not slip in (and get us to cache) records for other
+    // zones, e.g. glue pointing somebody else's nameserver elsewhere
+    pub fn filter_bailiwick(&mut self, zone: &str) {
+        for section in [&mut self.answers, &mut self.authorities, &mut self.resources].iter_mut() {
+            section.retain(|rec| {
+                if in_zone(rec.get_domain(), zone) {
+                    return true; }
+
+                println!("Dropping out of bailiwick record for zone {:?}: {:?}", zone, rec);
+                false
+            });
+        }
+    }
+
+    // Answers of the given type owned by name
+    pub fn get_answers_for(&self, name: &str, qtype: QueryType) -> Vec<DnsRecord> {
+        self.answers.iter()
+            .filter(|rec| rec.get_querytype() == qtype && rec.get_domain().eq_ignore_ascii_case(name))
+            .cloned()
+            .collect()
+    }
+
+    // Follows the aliases in answers from qname, to the name the rest of
+    // the response is about, e.g. the one an NXDOMAIN is for (RFC 6604)
+    pub fn get_chain_target(&self, qname: &str) -> String {
+        let mut name = qname.to_lowercase();
+        for _ in 0..self.answers.len() {
+            let next = match self.get_answers_for(&name, QueryType::CNAME).pop() {
+                Some(DnsRecord::CNAME { host, .. }) => host.to_lowercase(),
+                _ => match self.get_dname_for(&name).and_then(|dname| dname.dname_substitute(&name)) {
+                    Some(target) => target,
+                    None => break,
+                },
+            };
+            name = next;
+        }
+
+        name
+    }
+
+    // Signatures in the answers over the records of the given type owned by name
+    pub fn get_signatures_for(&self, name: &str, qtype: QueryType) -> Vec<DnsRecord> {
+        self.answers.iter()
+            .filter(|rec| match **rec {
+                DnsRecord::RRSIG { ref domain, type_covered, .. } =>
+                    type_covered == qtype && domain.eq_ignore_ascii_case(name),
+                _ => false,
+            })
+            .cloned()
+            .collect()
+    }
+
+    // Clients which did not set the DO bit get no DNSSEC records, unless
+    // they asked for that very type (RFC 4035 3.2.1)
+    pub fn strip_dnssec(&mut self, qtype: QueryType) {
+        for section in [&mut self.answers, &mut self.authorities, &mut self.resources].iter_mut() {
+            section.retain(|rec| {
+                let rtype = rec.get_querytype();
+                rtype == qtype ||
+                    (rtype != QueryType::RRSIG && rtype != QueryType::NSEC && rtype != QueryType::NSEC3)
+            });
+        }
+    }
+
+    // DNAME in the answers redirecting name, the deepest one if there
+    // are several
+    pub fn get_dname_for(&self, name: &str) -> Option<DnsRecord> {
+        let mut found: Option<&DnsRecord> = None;
+        for rec in &self.answers {
+            if rec.dname_substitute(name).is_none() {
+                continue; }
+
+            let deeper = match found {
+                Some(f) => rec.get_domain().len() > f.get_domain().len(),
+                None => true,
+            };
+            if deeper {
+                found = Some(rec); }
+        }
+
+        found.cloned()
+    }
+
     // Picks a list of random A records from packet
     // Doesnt matter which one as they will all lead
     // to the same place, however might want to find
     // a way to find the lowest latency
+    #[allow(dead_code)]
     pub fn get_random_a(&self) -> Option<String> {
         if !self.answers.is_empty() {
             let idx = random::<usize>() % self.answers.len();
@@ -100,48 +281,445 @@ impl DnsPacket {
         None
     }
 
-    pub fn get_resolved_ns(&self, qname: &str) -> Option<String> {
+    // Orders the SRV answers the way a client should try them (RFC 2782),
+    // lowest priority first and, within a priority, a weighted random pick
+    // so targets with a higher weight are more likely to come first
+    #[allow(dead_code)]
+    pub fn get_ordered_srv(&self) -> Vec<DnsRecord> {
+        self.order_srv(|total| random::<u32>() % (total + 1))
+    }
+
+    // pick gives a number from 0 to the total weight left in the group
+    fn order_srv<F>(&self, mut pick: F) -> Vec<DnsRecord>
+        where F: FnMut(u32) -> u32 {
+        // A lone record with "." as target means there is no such service
+        let srvs = self.answers.iter()
+            .filter(|rec| rec.get_querytype() == QueryType::SRV)
+            .collect::<Vec<&DnsRecord>>();
+        if let [DnsRecord::SRV { host, .. }] = srvs[..] {
+            if host.trim_end_matches('.').is_empty() {
+                return Vec::new(); }}
+
+        let mut by_priority: BTreeMap<u16, Vec<(u16, DnsRecord)>> = BTreeMap::new();
+        for rec in srvs {
+            if let DnsRecord::SRV { priority, weight, .. } = *rec {
+                by_priority.entry(priority).or_default().push((weight, rec.clone())); }}
+
+        let mut ordered = Vec::new();
+        for (_, mut group) in by_priority {
+            // Zero weight records go first so they still get a small chance
+            group.sort_by_key(|&(weight, _)| weight != 0);
+
+            while !group.is_empty() {
+                let total: u32 = group.iter().map(|&(weight, _)| weight as u32).sum();
+                let pick = pick(total);
+
+                let mut running = 0;
+                let mut idx = group.len() - 1;
+                for (i, &(weight, _)) in group.iter().enumerate() {
+                    running += weight as u32;
+                    if running >= pick {
+                        idx = i;
+                        break; }
+                }
+
+                ordered.push(group.remove(idx).1);
+            }
+        }
+
+        ordered
+    }
+
+    // Every A record address in the answers, in random order so the
+    // load is spread while the rest remain to fail over to
+    pub fn get_all_a(&self) -> Vec<String> {
+        let mut addrs = Vec::new();
+        for rec in &self.answers {
+            if let DnsRecord::A { ref addr, .. } = *rec {
+                addrs.push(addr.to_string()); }}
+
+        addrs.shuffle(&mut thread_rng());
+        addrs
+    }
+
+    // Zone the response refers us to, the deepest one with NS records
+    // in the authority section that the query name falls in
+    pub fn get_referral_zone(&self, qname: &str) -> Option<String> {
+        let mut zone: Option<String> = None;
+        for auth in &self.authorities {
+            if let DnsRecord::NS { ref domain, .. } = *auth {
+                if !in_zone(qname, domain) {
+                    continue; }
+
+                let deeper = match zone {
+                    Some(ref z) => domain.len() > z.len(),
+                    None => true,
+                };
+                // Lowercased, so it can be compared with the zones we know
+                if deeper {
+                    zone = Some(domain.to_lowercase()); }
+            }
+        }
+
+        zone
+    }
+
+    // Addresses of the nameservers we were referred to, for which the
+    // glue A records were included. Only glue within the bailiwick of the
+    // responding server should be left by now (see filter_bailiwick)
+    pub fn get_resolved_ns(&self, qname: &str) -> Vec<String> {
         let mut new_authorities = Vec::new();
         for auth in &self.authorities {
             if let DnsRecord::NS { ref domain, ref host, .. } = *auth {
-                if !qname.ends_with(domain) {
+                if !in_zone(qname, domain) {
                     continue; }
 
                 // Scan NS Record for matching
                 for rsrc in &self.resources {
-                    if let DnsRecord::A { ref domain, ref addr, ttl } = *rsrc {
-                        if domain != host {
+                    if let DnsRecord::A { ref domain, ref addr, .. } = *rsrc {
+                        if !domain.eq_ignore_ascii_case(host) {
                             continue; }
 
-                        let rec = DnsRecord::A {
-                            domain: host.clone(),
-                            addr: *addr,
-                            ttl: ttl };
-                        new_authorities.push(rec);
+                        new_authorities.push(addr.to_string());
                     }
                 }
             }
         }
-        // Picks the first match
-        if !new_authorities.is_empty() {
-            if let DnsRecord::A { addr, .. } = new_authorities[0] {
-                return Some(addr.to_string()); }}
-        None
+
+        new_authorities.shuffle(&mut thread_rng());
+        new_authorities
     }
 
-    pub fn get_unresolved_ns(&self, qname: &str) -> Option<String> {
+    // Names of the nameservers we were referred to, which still have
+    // to be resolved before they can be asked as they came without glue
+    pub fn get_unresolved_ns(&self, qname: &str) -> Vec<String> {
         let mut new_authorities = Vec::new();
         for auth in &self.authorities {
             if let DnsRecord::NS { ref domain, ref host, .. } = *auth {
-                if !qname.ends_with(domain) {
+                if !in_zone(qname, domain) {
                     continue; }
 
-                new_authorities.push(host); }}
+                let glued = self.resources.iter().any(|rsrc| match *rsrc {
+                    DnsRecord::A { ref domain, .. } => domain.eq_ignore_ascii_case(host),
+                    _ => false,
+                });
+                if !glued {
+                    new_authorities.push(host.clone()); }}}
 
-        if !new_authorities.is_empty() {
-            let idx = random::<usize>() % new_authorities.len();
-            return Some(new_authorities[idx].clone()); }
+        new_authorities.shuffle(&mut thread_rng());
+        new_authorities
+    }
+}
+#[cfg(test)]
+pub mod tests {
+    use super::*;
 
-        None
+    #[allow(clippy::redundant_field_names)]
+    fn srv(priority: u16, weight: u16, host: &str) -> DnsRecord {
+        DnsRecord::SRV {
+            domain: "_sip._tcp.example.com".to_string(),
+            priority: priority,
+            weight: weight,
+            port: 5060,
+            host: host.to_string(),
+            ttl: 300,
+        }
+    }
+
+    fn hosts(records: &[DnsRecord]) -> Vec<String> {
+        records.iter().map(|rec| match *rec {
+            DnsRecord::SRV { ref host, .. } => host.clone(),
+            _ => panic!("Not an SRV record: {:?}", rec),
+        }).collect()
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn srv_priorities_sort_ascending() {
+        let mut packet = DnsPacket::new();
+        packet.answers = vec![srv(30, 1, "c"), srv(10, 5, "a1"), srv(20, 0, "b"), srv(10, 5, "a2")];
+
+        for _ in 0..50 {
+            let ordered = packet.get_ordered_srv();
+            let priorities = ordered.iter().map(|rec| match *rec {
+                DnsRecord::SRV { priority, .. } => priority,
+                _ => unreachable!(),
+            }).collect::<Vec<u16>>();
+            assert_eq!(priorities, vec![10, 10, 20, 30]);
+        }
+    }
+
+    #[test]
+    fn srv_zero_weight_comes_first_in_its_group() {
+        let mut packet = DnsPacket::new();
+        packet.answers = vec![srv(10, 5, "a"), srv(10, 0, "zero"), srv(10, 10, "c")];
+
+        // The lowest pick takes the first record of the running sum
+        assert_eq!(hosts(&packet.order_srv(|_| 0)), vec!["zero", "a", "c"]);
+        // The highest pick takes the last one
+        assert_eq!(hosts(&packet.order_srv(|total| total)), vec!["c", "a", "zero"]);
+    }
+
+    #[test]
+    fn srv_weights_select_by_running_sum() {
+        let mut packet = DnsPacket::new();
+        packet.answers = vec![srv(10, 1, "a"), srv(10, 9, "b")];
+
+        assert_eq!(hosts(&packet.order_srv(|_| 1)), vec!["a", "b"]);
+        assert_eq!(hosts(&packet.order_srv(|_| 2)), vec!["b", "a"]);
+    }
+
+    #[test]
+    fn srv_lone_root_target_means_unavailable() {
+        let mut packet = DnsPacket::new();
+        packet.answers = vec![srv(0, 0, "")];
+        assert!(packet.get_ordered_srv().is_empty());
+
+        packet.answers = vec![srv(0, 0, ".")];
+        assert!(packet.get_ordered_srv().is_empty());
+
+        // Only a record on its own says so
+        packet.answers = vec![srv(0, 0, ""), srv(10, 0, "a")];
+        assert_eq!(packet.get_ordered_srv().len(), 2);
+    }
+
+    fn a_record(n: u8) -> DnsRecord {
+        DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: std::net::Ipv4Addr::new(192, 0, 2, n),
+            ttl: 300,
+        }
+    }
+
+    #[test]
+    fn write_truncates_when_out_of_room() {
+        let mut packet = DnsPacket::new();
+        packet.answers = (0..100).map(a_record).collect();
+
+        let mut buffer = BytePacketBuffer::with_max_size(512);
+        packet.write(&mut buffer).unwrap();
+        assert!(packet.header.truncated_message);
+        assert!(packet.header.answers > 0 && packet.header.answers < 100);
+        assert!(buffer.pos() <= 512);
+    }
+
+    fn packet_round_trip(packet: &mut DnsPacket, max_size: usize) -> DnsPacket {
+        let mut buffer = BytePacketBuffer::with_max_size(max_size);
+        packet.write(&mut buffer).unwrap();
+        let mut buffer = BytePacketBuffer::from_bytes(&buffer.buf[..buffer.pos()]);
+        let read = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(buffer.pos(), buffer.buf.len());
+        read
+    }
+
+    #[test]
+    fn compressed_packets_round_trip() {
+        let mut packet = DnsPacket::new();
+        packet.questions.push(DnsQuestion::new("www.example.com".to_string(), QueryType::A));
+        packet.answers = vec![cname("www.example.com", "mail.example.com"), a_record(1)];
+        packet.authorities = vec![ns("example.com", "ns1.example.com"), ns("example.com", "ns.example.net")];
+        packet.resources = vec![DnsRecord::MX {
+            domain: "example.com".to_string(),
+            priority: 10,
+            host: "mail.example.com".to_string(),
+            ttl: 300,
+        }];
+
+        let read = packet_round_trip(&mut packet, 512);
+        assert_eq!(read.questions, packet.questions);
+        assert_eq!(read.answers, packet.answers);
+        assert_eq!(read.authorities, packet.authorities);
+        assert_eq!(read.resources, packet.resources);
+    }
+
+    #[test]
+    fn truncated_packets_round_trip() {
+        // Whatever made it in before the cut, and the OPT record after
+        // it, still have to read back
+        let mut packet = DnsPacket::new();
+        packet.answers = (0..40).map(|n| cname(&format!("a{}.example.com", n), "example.com")).collect();
+        packet.edns = Some(EdnsOpt::new());
+
+        let read = packet_round_trip(&mut packet, 512);
+        assert!(read.header.truncated_message);
+        assert!(read.edns.is_some());
+        assert_eq!(read.answers[..], packet.answers[..read.answers.len()]);
+    }
+
+    #[test]
+    fn write_fails_on_records_which_can_not_be_encoded() {
+        let mut packet = DnsPacket::new();
+        packet.answers.push(DnsRecord::CNAME {
+            domain: "example.com".to_string(),
+            host: format!("{}.example.com", "a".repeat(70)),
+            ttl: 300,
+        });
+
+        let mut buffer = BytePacketBuffer::with_max_size(512);
+        assert!(packet.write(&mut buffer).is_err());
+    }
+
+    fn rcode_round_trip(rescode: ResultCode, edns: Option<EdnsOpt>) -> ResultCode {
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+        packet.header.rescode = rescode;
+        packet.edns = edns;
+
+        let mut buffer = BytePacketBuffer::with_max_size(512);
+        packet.write(&mut buffer).unwrap();
+        let mut buffer = BytePacketBuffer::from_bytes(&buffer.buf[..buffer.pos()]);
+        DnsPacket::from_buffer(&mut buffer).unwrap().header.rescode
+    }
+
+    #[test]
+    fn extended_rcodes_need_edns() {
+        assert_eq!(rcode_round_trip(ResultCode::BADCOOKIE, Some(EdnsOpt::new())), ResultCode::BADCOOKIE);
+        assert_eq!(rcode_round_trip(ResultCode::BADVERS, Some(EdnsOpt::new())), ResultCode::BADVERS);
+        assert_eq!(rcode_round_trip(ResultCode::BADCOOKIE, None), ResultCode::SERVFAIL);
+        assert_eq!(rcode_round_trip(ResultCode::NXDOMAIN, None), ResultCode::NXDOMAIN);
+    }
+
+    fn nsec(domain: &str) -> DnsRecord {
+        DnsRecord::NSEC {
+            domain: domain.to_string(),
+            next_domain: "z.example.com".to_string(),
+            types: vec![QueryType::A, QueryType::RRSIG, QueryType::NSEC],
+            ttl: 300,
+        }
+    }
+
+    // Signature which only has to look the part, shared with the cache tests
+    #[allow(clippy::redundant_field_names)]
+    pub fn rrsig(domain: &str, type_covered: QueryType) -> DnsRecord {
+        DnsRecord::RRSIG {
+            domain: domain.to_string(),
+            type_covered: type_covered,
+            algorithm: 13,
+            labels: 2,
+            original_ttl: 300,
+            expiration: 1900000000,
+            inception: 1700000000,
+            key_tag: 1,
+            signer: "example.com".to_string(),
+            signature: vec![0; 64],
+            ttl: 300,
+        }
+    }
+
+    fn signed_nodata() -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.answers = vec![a_record(1), rrsig("example.com", QueryType::A)];
+        packet.authorities = vec![nsec("example.com"), rrsig("example.com", QueryType::NSEC)];
+        packet
+    }
+
+    #[test]
+    fn dnssec_records_are_stripped() {
+        let mut packet = signed_nodata();
+        packet.strip_dnssec(QueryType::A);
+
+        assert_eq!(packet.answers, vec![a_record(1)]);
+        assert!(packet.authorities.is_empty());
+    }
+
+    #[test]
+    fn dnssec_records_asked_for_are_kept() {
+        let mut packet = signed_nodata();
+        packet.strip_dnssec(QueryType::NSEC);
+        assert_eq!(packet.authorities, vec![nsec("example.com")]);
+
+        let mut packet = signed_nodata();
+        packet.strip_dnssec(QueryType::RRSIG);
+        assert_eq!(packet.answers.len(), 2);
+        assert_eq!(packet.authorities, vec![rrsig("example.com", QueryType::NSEC)]);
+    }
+
+    #[test]
+    fn signatures_are_found_by_covered_type() {
+        let packet = signed_nodata();
+        assert_eq!(packet.get_signatures_for("EXAMPLE.com", QueryType::A),
+                   vec![rrsig("example.com", QueryType::A)]);
+        assert!(packet.get_signatures_for("example.com", QueryType::AAAA).is_empty());
+    }
+
+    fn cname(domain: &str, host: &str) -> DnsRecord {
+        DnsRecord::CNAME {
+            domain: domain.to_string(),
+            host: host.to_string(),
+            ttl: 300,
+        }
+    }
+
+    #[test]
+    fn chain_target_follows_aliases() {
+        let mut packet = DnsPacket::new();
+        packet.answers = vec![cname("b.example.com", "C.example.net"), cname("A.example.com", "b.example.com")];
+        assert_eq!(packet.get_chain_target("a.EXAMPLE.com"), "c.example.net");
+        assert_eq!(packet.get_chain_target("other.example.com"), "other.example.com");
+
+        // Loops end once every record could have been followed
+        packet.answers = vec![cname("a.example.com", "b.example.com"), cname("b.example.com", "a.example.com")];
+        assert_eq!(packet.get_chain_target("a.example.com"), "a.example.com");
+    }
+
+    #[test]
+    fn in_zone_only_folds_ascii_case() {
+        assert!(in_zone("WWW.Example.COM.", "example.com"));
+        assert!(!in_zone("www.example.com", "ample.com"));
+        // The Kelvin sign lowercases to an ASCII k in Unicode, not in DNS
+        assert!(!in_zone("a.\u{212A}.com", "k.com"));
+        assert!(!in_zone("a.k.com", "\u{212A}.com"));
+    }
+
+    fn ns(domain: &str, host: &str) -> DnsRecord {
+        DnsRecord::NS {
+            domain: domain.to_string(),
+            host: host.to_string(),
+            ttl: 3600,
+        }
+    }
+
+    #[test]
+    fn nameservers_without_glue_are_left_to_resolve() {
+        let mut packet = DnsPacket::new();
+        packet.authorities = vec![ns("example.com", "ns1.example.com"), ns("example.com", "ns.example.net")];
+        packet.resources = vec![DnsRecord::A {
+            domain: "NS1.example.com".to_string(),
+            addr: std::net::Ipv4Addr::new(192, 0, 2, 53),
+            ttl: 3600,
+        }];
+
+        assert_eq!(packet.get_resolved_ns("www.example.com"), vec!["192.0.2.53".to_string()]);
+        assert_eq!(packet.get_unresolved_ns("www.example.com"), vec!["ns.example.net".to_string()]);
+        assert!(packet.get_unresolved_ns("www.example.org").is_empty());
+    }
+
+    fn glue(domain: &str, n: u8) -> DnsRecord {
+        DnsRecord::A {
+            domain: domain.to_string(),
+            addr: std::net::Ipv4Addr::new(192, 0, 2, n),
+            ttl: 3600,
+        }
+    }
+
+    #[test]
+    fn records_out_of_bailiwick_are_dropped() {
+        // A referral from a com server to example.com, with glue for its
+        // own nameserver, a sibling zone's under com and one elsewhere
+        let mut packet = DnsPacket::new();
+        packet.answers = vec![glue("www.example.org", 1)];
+        packet.authorities = vec![ns("example.com", "ns.example.com"), ns("example.com", "ns.sibling.com"),
+                                  ns("example.com", "ns.example.net")];
+        packet.resources = vec![glue("ns.example.com", 1), glue("NS.Sibling.COM", 2), glue("ns.example.net", 3)];
+
+        packet.filter_bailiwick("com");
+        assert!(packet.answers.is_empty());
+        assert_eq!(packet.authorities.len(), 3);
+        assert_eq!(packet.resources, vec![glue("ns.example.com", 1), glue("NS.Sibling.COM", 2)]);
+
+        // The root servers have a say over everything
+        let mut packet = DnsPacket::new();
+        packet.resources = vec![glue("ns.example.org", 3), glue("ns.example.net", 2)];
+        packet.filter_bailiwick("");
+        assert_eq!(packet.resources.len(), 2);
+    }
+}
@@ -0,0 +1,1078 @@
+extern crate ring;
+use super::{
+    BytePacketBuffer,
+    DnsPacket,
+    DnsRecord,
+    QueryType,
+    ResultCode,
+    EdeCode,
+    ExtendedError,
+    in_zone,
+    TCP_MAX_SIZE,
+    };
+
+use ring::digest;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use std::cmp;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io::Error;
+use std::io::ErrorKind::{InvalidInput, Other};
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// DS records of the root KSKs (KSK-2017 and KSK-2024), as published by
+// IANA in root-anchors.xml
+const ROOT_ANCHORS: [(u16, u8, u8, &str); 2] = [
+    (20326, 8, 2, "e06d44b80b8f1d39a95c0b0d7c65d08458e880409bbc683457104237c7f8ec8d"),
+    (38696, 8, 2, "683d2d0acb8c9b712a1948b27f741219298d0a450d612c483af444a4c0fb2b16"),
+];
+
+// Verified keys are looked up again after at most an hour, as is
+// whether a zone is signed at all
+const MAX_KEY_TTL: u32 = 3600;
+// NSEC3 hashing is costly, proofs with more iterations are refused (RFC 9276)
+const MAX_NSEC3_ITERATIONS: u16 = 150;
+
+// DNSKEY flags (RFC 4034 2.1.1, RFC 5011)
+const ZONE_KEY_FLAG: u16 = 0x0100;
+const REVOKE_FLAG: u16 = 0x0080;
+// NSEC3 flag of spans which may hold unsigned delegations
+const OPT_OUT_FLAG: u8 = 0x01;
+
+// Lowercase base32 with the extended hex alphabet, as used in NSEC3 owners
+const BASE32HEX: &[u8] = b"0123456789abcdefghijklmnopqrstuv";
+
+// Current time as used in RRSIG validity windows
+pub fn unix_now() -> u32 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+// Validation failures carry the reason for the client as an EDE
+fn bogus(code: EdeCode, text: String) -> Error {
+    Error::new(Other, ExtendedError::new(code, &text))
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+        return None; }
+
+    (0..hex.len() / 2)
+        .map(|i| u8::from_str_radix(&hex[i*2..i*2+2], 16).ok())
+        .collect()
+}
+
+fn decode_base32hex(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut bits: u32 = 0;
+    let mut count = 0;
+
+    for c in text.bytes() {
+        let val = BASE32HEX.iter().position(|x| *x == c.to_ascii_lowercase())? as u32;
+        bits = (bits << 5) | val;
+        count += 5;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+            bits &= (1 << count) - 1;
+        }
+    }
+
+    Some(out)
+}
+
+fn labels_of(name: &str) -> Vec<&str> {
+    name.trim_end_matches('.')
+        .split('.')
+        .filter(|label| !label.is_empty())
+        .collect()
+}
+
+// Number of labels as counted by the RRSIG labels field, which leaves
+// out the root and a leading wildcard
+fn label_count(name: &str) -> usize {
+    let labels = labels_of(name);
+    match labels.first() {
+        Some(&"*") => labels.len() - 1,
+        _ => labels.len(),
+    }
+}
+
+// Name made of the last count labels of name
+fn last_labels(name: &str, count: usize) -> String {
+    let labels = labels_of(name);
+    labels[labels.len() - count..].join(".")
+}
+
+fn wildcard_of(name: &str) -> String {
+    if name.is_empty() {
+        return "*".to_string(); }
+    format!("*.{}", name)
+}
+
+// Canonical name order (RFC 4034 6.1), label by label starting at the root
+fn canonical_cmp(a: &str, b: &str) -> Ordering {
+    let key = |name: &str| labels_of(name).iter()
+        .rev()
+        .map(|label| label.to_ascii_lowercase().into_bytes())
+        .collect::<Vec<Vec<u8>>>();
+
+    key(a).cmp(&key(b))
+}
+
+// Deepest name both a and b are in
+fn common_ancestor(a: &str, b: &str) -> String {
+    let a = labels_of(a);
+    let b = labels_of(b);
+    let same = a.iter().rev()
+        .zip(b.iter().rev())
+        .take_while(|(x, y)| x.eq_ignore_ascii_case(y))
+        .count();
+
+    a[a.len() - same..].join(".").to_lowercase()
+}
+
+fn canonical_name(name: &str) -> Result<Vec<u8>, Error> {
+    let mut buffer = BytePacketBuffer::with_max_size(TCP_MAX_SIZE);
+    buffer.set_canonical(true);
+    buffer.write_qname(name)?;
+
+    Ok(buffer.buf[..buffer.pos()].to_vec())
+}
+
+// Canonical wire form of a record, split into the owner name and the rest
+// (type, class, ttl, rdata length and rdata)
+fn canonical_record(record: &DnsRecord) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let mut buffer = BytePacketBuffer::with_max_size(TCP_MAX_SIZE);
+    buffer.set_canonical(true);
+    record.write(&mut buffer)?;
+
+    let name_len = canonical_name(record.get_domain())?.len();
+    let data = &buffer.buf[..buffer.pos()];
+    Ok((data[..name_len].to_vec(), data[name_len..].to_vec()))
+}
+
+fn canonical_rdata(record: &DnsRecord) -> Result<Vec<u8>, Error> {
+    let (_, rest) = canonical_record(record)?;
+    Ok(rest[10..].to_vec())
+}
+
+// Key tag of a DNSKEY (RFC 4034 Appendix B)
+fn key_tag(rdata: &[u8]) -> u16 {
+    let mut ac: u32 = 0;
+    for (i, b) in rdata.iter().enumerate() {
+        if i & 1 == 1 {
+            ac += *b as u32;
+        } else {
+            ac += (*b as u32) << 8;
+        }
+    }
+    ac += (ac >> 16) & 0xFFFF;
+
+    (ac & 0xFFFF) as u16
+}
+
+// RSA/SHA-256, RSA/SHA-512, ECDSA P-256, ECDSA P-384 and Ed25519
+fn supported_algorithm(algorithm: u8) -> bool {
+    matches!(algorithm, 8 | 10 | 13 | 14 | 15)
+}
+
+fn ds_digest(digest_type: u8, data: &[u8]) -> Option<Vec<u8>> {
+    let algorithm = match digest_type {
+        1 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+        2 => &digest::SHA256,
+        4 => &digest::SHA384,
+        _ => return None,
+    };
+
+    Some(digest::digest(algorithm, data).as_ref().to_vec())
+}
+
+// Whether a DS record can be checked at all, DS records which can not
+// are skipped as if they were not there (RFC 4035 5.2)
+fn supported_ds(ds: &DnsRecord) -> bool {
+    match *ds {
+        DnsRecord::DS { algorithm, digest_type, .. } =>
+            supported_algorithm(algorithm) && ds_digest(digest_type, &[]).is_some(),
+        _ => false,
+    }
+}
+
+// Whether ds vouches for key, its digest being over the owner and rdata
+fn ds_matches(ds: &DnsRecord, key: &DnsRecord) -> Result<bool, Error> {
+    let (ds_tag, ds_algorithm, digest_type, digest) = match *ds {
+        DnsRecord::DS { key_tag, algorithm, digest_type, ref digest, .. } =>
+            (key_tag, algorithm, digest_type, digest),
+        _ => return Ok(false),
+    };
+    let (flags, algorithm) = match *key {
+        DnsRecord::DNSKEY { flags, algorithm, .. } => (flags, algorithm),
+        _ => return Ok(false),
+    };
+
+    if flags & ZONE_KEY_FLAG == 0 || flags & REVOKE_FLAG > 0 || algorithm != ds_algorithm {
+        return Ok(false); }
+    if !ds.get_domain().eq_ignore_ascii_case(key.get_domain()) {
+        return Ok(false); }
+
+    let rdata = canonical_rdata(key)?;
+    if key_tag(&rdata) != ds_tag {
+        return Ok(false); }
+
+    let mut data = canonical_name(key.get_domain())?;
+    data.extend_from_slice(&rdata);
+    Ok(ds_digest(digest_type, &data).as_ref() == Some(digest))
+}
+
+// RSA public keys are the exponent length, exponent and modulus (RFC 3110)
+fn verify_rsa(params: &'static signature::RsaParameters, key: &[u8],
+              message: &[u8], sig: &[u8]) -> bool {
+    let (exp_len, offset) = match key.first() {
+        Some(0) if key.len() >= 3 => (((key[1] as usize) << 8) | key[2] as usize, 3),
+        Some(&len) => (len as usize, 1),
+        None => return false,
+    };
+    if key.len() <= offset + exp_len {
+        return false; }
+
+    // Without leading zeros, which ring does not accept
+    let strip = |x: &[u8]| x.iter().position(|b| *b != 0).map_or(Vec::new(), |i| x[i..].to_vec());
+    let e = strip(&key[offset..offset + exp_len]);
+    let n = strip(&key[offset + exp_len..]);
+
+    RsaPublicKeyComponents { n: &n, e: &e }.verify(params, message, sig).is_ok()
+}
+
+fn verify_signature(algorithm: u8, key: &[u8], message: &[u8], sig: &[u8]) -> bool {
+    match algorithm {
+        // RSA/SHA-256 and RSA/SHA-512, zones still use 1024 bit keys
+        8 => verify_rsa(&signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY, key, message, sig),
+        10 => verify_rsa(&signature::RSA_PKCS1_1024_8192_SHA512_FOR_LEGACY_USE_ONLY, key, message, sig),
+        // ECDSA keys are the bare point, signatures r and s (RFC 6605)
+        13 | 14 => {
+            let mut point = vec![0x04];
+            point.extend_from_slice(key);
+
+            let algorithm = match algorithm {
+                13 => &signature::ECDSA_P256_SHA256_FIXED,
+                _ => &signature::ECDSA_P384_SHA384_FIXED,
+            };
+            UnparsedPublicKey::new(algorithm, &point).verify(message, sig).is_ok()
+        },
+        15 => UnparsedPublicKey::new(&signature::ED25519, key).verify(message, sig).is_ok(),
+        _ => false,
+    }
+}
+
+// Splits records into RRsets, leaving out signatures
+fn rrsets(records: &[DnsRecord]) -> Vec<Vec<DnsRecord>> {
+    let mut sets: Vec<Vec<DnsRecord>> = Vec::new();
+    for rec in records {
+        if rec.get_querytype() == QueryType::RRSIG {
+            continue; }
+
+        let found = sets.iter_mut().find(|set| {
+            set[0].get_querytype() == rec.get_querytype() &&
+                set[0].get_domain().eq_ignore_ascii_case(rec.get_domain())
+        });
+        match found {
+            Some(set) => set.push(rec.clone()),
+            None => sets.push(vec![rec.clone()]),
+        }
+    }
+
+    sets
+}
+
+fn signatures_for(records: &[DnsRecord], owner: &str, qtype: QueryType) -> Vec<DnsRecord> {
+    records.iter()
+        .filter(|rec| match **rec {
+            DnsRecord::RRSIG { ref domain, type_covered, .. } =>
+                type_covered == qtype && domain.eq_ignore_ascii_case(owner),
+            _ => false,
+        })
+        .cloned()
+        .collect()
+}
+
+// Checks a single signature over an RRset, on success returns the name
+// the RRset was expanded from when it came from a wildcard
+fn verify_rrsig(rrset: &[DnsRecord], rrsig: &DnsRecord, keys: &[DnsRecord],
+                now: u32) -> Result<Option<String>, Error> {
+    let (labels, original_ttl, expiration, inception, sig_tag, signer, sig_algorithm) = match *rrsig {
+        DnsRecord::RRSIG { labels, original_ttl, expiration, inception, key_tag,
+                           ref signer, algorithm, .. } =>
+            (labels, original_ttl, expiration, inception, key_tag, signer, algorithm),
+        _ => return Err(Error::new(InvalidInput, "Not an RRSIG record")),
+    };
+    let owner = rrset[0].get_domain();
+    let qtype = rrset[0].get_querytype();
+
+    if !in_zone(owner, signer) {
+        return Err(bogus(EdeCode::DNSSEC_BOGUS, format!(
+            "RRSIG for {:?} {} is signed by {}, which is not above it", qtype, owner, signer))) }
+    if labels as usize > label_count(owner) {
+        return Err(bogus(EdeCode::DNSSEC_BOGUS, format!(
+            "RRSIG for {:?} {} has too many labels", qtype, owner))) }
+
+    // Serial number arithmetic, the times wrap round in 2106 (RFC 4034 3.1.5)
+    if (now.wrapping_sub(inception) as i32) < 0 {
+        return Err(bogus(EdeCode::SIGNATURE_NOT_YET_VALID, format!(
+            "RRSIG for {:?} {} is not valid yet", qtype, owner))) }
+    if (expiration.wrapping_sub(now) as i32) < 0 {
+        return Err(bogus(EdeCode::SIGNATURE_EXPIRED, format!(
+            "RRSIG for {:?} {} has expired", qtype, owner))) }
+
+    // A record expanded from a wildcard is signed as the wildcard itself
+    let wildcard = if (labels as usize) < label_count(owner) {
+        Some(last_labels(owner, labels as usize))
+    } else {
+        None
+    };
+    let signed_owner = match wildcard {
+        Some(ref closest) => canonical_name(&wildcard_of(closest))?,
+        None => canonical_name(owner)?,
+    };
+
+    // Signed data is the RRSIG rdata without the signature, followed by
+    // the records in canonical order with their original TTL (RFC 4034 3.1.8.1)
+    let mut unsigned = rrsig.clone();
+    if let DnsRecord::RRSIG { ref mut signature, .. } = unsigned {
+        signature.clear(); }
+    let mut data = canonical_rdata(&unsigned)?;
+
+    let mut records = Vec::new();
+    for rec in rrset {
+        let mut rec = rec.clone();
+        rec.set_ttl(original_ttl);
+        records.push(canonical_record(&rec)?.1);
+    }
+    records.sort_by(|a, b| a[10..].cmp(&b[10..]));
+    records.dedup();
+    for rec in records {
+        data.extend_from_slice(&signed_owner);
+        data.extend_from_slice(&rec);
+    }
+
+    let signature = match *rrsig {
+        DnsRecord::RRSIG { ref signature, .. } => signature,
+        _ => return Err(Error::new(InvalidInput, "Not an RRSIG record")),
+    };
+
+    let mut candidates = 0;
+    for key in keys {
+        if let DnsRecord::DNSKEY { ref domain, flags, protocol, algorithm, ref public_key, .. } = *key {
+            if !domain.eq_ignore_ascii_case(signer) || protocol != 3 || algorithm != sig_algorithm ||
+                flags & ZONE_KEY_FLAG == 0 || flags & REVOKE_FLAG > 0 {
+                continue; }
+            if key_tag(&canonical_rdata(key)?) != sig_tag {
+                continue; }
+
+            candidates += 1;
+            if verify_signature(algorithm, public_key, &data, signature) {
+                return Ok(wildcard); }
+        }
+    }
+
+    if candidates == 0 {
+        return Err(bogus(EdeCode::DNSKEY_MISSING, format!(
+            "No DNSKEY {} of {} for RRSIG over {:?} {}", sig_tag, signer, qtype, owner))) }
+
+    Err(bogus(EdeCode::DNSSEC_BOGUS, format!(
+        "RRSIG over {:?} {} does not verify", qtype, owner)))
+}
+
+// An RRset is good when any of its signatures verifies with any of the keys
+fn verify_rrset(rrset: &[DnsRecord], rrsigs: &[DnsRecord], keys: &[DnsRecord],
+                now: u32) -> Result<Option<String>, Error> {
+    let mut last_err = bogus(EdeCode::RRSIGS_MISSING, format!(
+        "No RRSIG for {:?} {}", rrset[0].get_querytype(), rrset[0].get_domain()));
+
+    for rrsig in rrsigs {
+        match verify_rrsig(rrset, rrsig, keys, now) {
+            Ok(wildcard) => return Ok(wildcard),
+            Err(e) => last_err = e,
+        }
+    }
+
+    Err(last_err)
+}
+
+// Salt, iterations, next hashed owner, types and flags of an NSEC3 record
+type Nsec3Fields<'a> = (&'a [u8], u16, &'a [u8], &'a [QueryType], u8);
+
+// Hash an NSEC3 record stands for, and the hash of the name checked against it
+type Nsec3Hashes = (Vec<u8>, Vec<u8>);
+
+// Only SHA-1 is defined for NSEC3, records with any other hash are ignored
+fn nsec3_params(record: &DnsRecord) -> Option<Nsec3Fields<'_>> {
+    match *record {
+        DnsRecord::NSEC3 { hash_algorithm: 1, ref salt, iterations, ref next_hashed, ref types, flags, .. } =>
+            Some((salt, iterations, next_hashed, types, flags)),
+        _ => None,
+    }
+}
+
+// Hashed owner name of NSEC3 (RFC 5155 5), SHA-1 repeated over the name and salt
+fn nsec3_hash(name: &str, salt: &[u8], iterations: u16) -> Result<Vec<u8>, Error> {
+    let mut data = canonical_name(name)?;
+    data.extend_from_slice(salt);
+    let mut hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &data).as_ref().to_vec();
+
+    for _ in 0..iterations {
+        let mut data = hash.clone();
+        data.extend_from_slice(salt);
+        hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &data).as_ref().to_vec();
+    }
+
+    Ok(hash)
+}
+
+// Only names in the zone of the record can be checked against it
+fn nsec3_hashes(record: &DnsRecord, name: &str) -> Result<Option<Nsec3Hashes>, Error> {
+    let (salt, iterations, ..) = match nsec3_params(record) {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+
+    let owner = record.get_domain();
+    let (label, zone) = match owner.find('.') {
+        Some(idx) => (&owner[..idx], &owner[idx+1..]),
+        None => (owner, ""),
+    };
+    if !in_zone(name, zone) {
+        return Ok(None); }
+
+    let owner_hash = match decode_base32hex(label) {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    Ok(Some((owner_hash, nsec3_hash(name, salt, iterations)?)))
+}
+
+fn nsec3_matches(record: &DnsRecord, name: &str) -> Result<bool, Error> {
+    Ok(match nsec3_hashes(record, name)? {
+        Some((owner_hash, hash)) => owner_hash == hash,
+        None => false,
+    })
+}
+
+fn nsec3_covers(record: &DnsRecord, name: &str) -> Result<bool, Error> {
+    let next = match nsec3_params(record) {
+        Some((_, _, next, ..)) => next.to_vec(),
+        None => return Ok(false),
+    };
+
+    Ok(match nsec3_hashes(record, name)? {
+        // The last record wraps round to the first hash in the zone
+        Some((owner_hash, hash)) => if owner_hash < next {
+            owner_hash < hash && hash < next
+        } else {
+            owner_hash < hash || hash < next
+        },
+        None => false,
+    })
+}
+
+fn nsec_covers(record: &DnsRecord, name: &str) -> bool {
+    match *record {
+        DnsRecord::NSEC { ref domain, ref next_domain, .. } => {
+            let after_owner = canonical_cmp(domain, name) == Ordering::Less;
+            let before_next = canonical_cmp(name, next_domain) == Ordering::Less;
+
+            // The last record points back to the apex
+            if canonical_cmp(domain, next_domain) == Ordering::Less {
+                after_owner && before_next
+            } else {
+                after_owner || before_next
+            }
+        },
+        _ => false,
+    }
+}
+
+// Types an NSEC or NSEC3 record lists for exactly name
+fn types_at<'a>(records: &'a [DnsRecord], name: &str) -> Result<Option<&'a [QueryType]>, Error> {
+    for rec in records {
+        match *rec {
+            DnsRecord::NSEC { ref domain, ref types, .. } if domain.eq_ignore_ascii_case(name) =>
+                return Ok(Some(types)),
+            DnsRecord::NSEC3 { ref types, .. } if nsec3_matches(rec, name)? =>
+                return Ok(Some(types)),
+            _ => {},
+        }
+    }
+
+    Ok(None)
+}
+
+fn any_covers(records: &[DnsRecord], name: &str) -> Result<bool, Error> {
+    for rec in records {
+        if nsec_covers(rec, name) || nsec3_covers(rec, name)? {
+            return Ok(true); }
+    }
+
+    Ok(false)
+}
+
+// Closest encloser proof (RFC 5155 7.2.1), returns the closest encloser
+// and the next closer name once a matching NSEC3 for the first is found
+fn closest_encloser(records: &[DnsRecord], name: &str) -> Result<Option<(String, String)>, Error> {
+    let labels = labels_of(name);
+    for i in 1..labels.len() + 1 {
+        let encloser = labels[i..].join(".");
+        for rec in records {
+            if nsec3_matches(rec, &encloser)? {
+                return Ok(Some((encloser, labels[i-1..].join(".")))); }
+        }
+    }
+
+    Ok(None)
+}
+
+// Closest encloser of a name shown not to exist by an NSEC record, which
+// is as far as its owner or next name goes up the tree
+fn nsec_closest_encloser(records: &[DnsRecord], name: &str) -> Option<String> {
+    records.iter()
+        .find(|rec| nsec_covers(rec, name))
+        .map(|rec| match *rec {
+            DnsRecord::NSEC { ref domain, ref next_domain, .. } => {
+                let a = common_ancestor(name, domain);
+                let b = common_ancestor(name, next_domain);
+                if a.len() > b.len() { a } else { b }
+            },
+            _ => String::new(),
+        })
+}
+
+fn proves_nxdomain(records: &[DnsRecord], name: &str) -> Result<bool, Error> {
+    // Neither the name nor a wildcard which could have stood in for it exist
+    if let Some(encloser) = nsec_closest_encloser(records, name) {
+        if any_covers(records, &wildcard_of(&encloser))? {
+            return Ok(true); }
+    }
+
+    if let Some((encloser, next_closer)) = closest_encloser(records, name)? {
+        return Ok(any_covers(records, &next_closer)? &&
+            any_covers(records, &wildcard_of(&encloser))?);
+    }
+
+    Ok(false)
+}
+
+fn proves_nodata(records: &[DnsRecord], name: &str, qtype: QueryType) -> Result<bool, Error> {
+    let lacks = |types: &[QueryType]| {
+        let lacking = !types.contains(&qtype) && !types.contains(&QueryType::CNAME);
+        // Proof of no DS has to come from the parent side of a delegation
+        // (RFC 4035 5.2, RFC 5155 8.9), any other name is no zone at all
+        if qtype == QueryType::DS {
+            return lacking && types.contains(&QueryType::NS) && !types.contains(&QueryType::SOA); }
+        lacking
+    };
+
+    if let Some(types) = types_at(records, name)? {
+        return Ok(lacks(types)); }
+
+    // The name may only exist through a wildcard without the type
+    if let Some(encloser) = nsec_closest_encloser(records, name) {
+        if let Some(types) = types_at(records, &wildcard_of(&encloser))? {
+            return Ok(lacks(types)); }
+    }
+
+    if let Some((encloser, next_closer)) = closest_encloser(records, name)? {
+        // Unsigned delegations may be skipped by an opt-out span (RFC 5155 8.6)
+        if qtype == QueryType::DS {
+            for rec in records {
+                if let Some((.., flags)) = nsec3_params(rec) {
+                    if flags & OPT_OUT_FLAG > 0 && nsec3_covers(rec, &next_closer)? {
+                        return Ok(true); }}}
+        }
+
+        if any_covers(records, &next_closer)? {
+            if let Some(types) = types_at(records, &wildcard_of(&encloser))? {
+                return Ok(lacks(types)); }
+        }
+    }
+
+    Ok(false)
+}
+
+fn security(secure: bool) -> Security {
+    if secure { Security::SECURE } else { Security::INSECURE }
+}
+
+// Parses DS records in presentation form, one per line, e.g.
+// ". 86400 IN DS 20326 8 2 E06D44B8...". Comments start with ;
+pub fn parse_anchors(text: &str) -> Result<Vec<DnsRecord>, Error> {
+    let mut anchors = Vec::new();
+
+    for line in text.lines() {
+        let line = line.split(';').next().unwrap_or("");
+        let fields = line.split_whitespace().collect::<Vec<&str>>();
+        if fields.is_empty() {
+            continue; }
+
+        let invalid = || Error::new(InvalidInput, format!("Invalid trust anchor: {}", line.trim()));
+        let idx = fields.iter().position(|x| x.eq_ignore_ascii_case("DS")).ok_or_else(invalid)?;
+        if fields.len() < idx + 5 {
+            return Err(invalid()) }
+
+        anchors.push(DnsRecord::DS {
+            domain: fields[0].trim_end_matches('.').to_lowercase(),
+            key_tag: fields[idx+1].parse().map_err(|_| invalid())?,
+            algorithm: fields[idx+2].parse().map_err(|_| invalid())?,
+            digest_type: fields[idx+3].parse().map_err(|_| invalid())?,
+            // The digest may be split into groups by whitespace
+            digest: decode_hex(&fields[idx+4..].concat()).ok_or_else(invalid)?,
+            ttl: 0,
+        });
+    }
+
+    Ok(anchors)
+}
+
+// Outcome of validating a response (RFC 4035 4.3), bogus ones are errors
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Security {
+    SECURE,
+    // Proven to be outside of the chain of trust, e.g. below an unsigned delegation
+    INSECURE,
+    // Nothing could be checked, e.g. the response has an unexpected rcode
+    INDETERMINATE,
+}
+
+struct ZoneKeys {
+    // None for zones proven to be unsigned
+    keys: Option<Vec<DnsRecord>>,
+    expires: Instant,
+}
+
+// Builds the chain of trust down from the root trust anchor and checks
+// responses against it. Records it needs along the way are asked for
+// through fetch, so it can be used without a network as well
+pub struct Validator {
+    // DS records for the keys of the zones anchored, usually just the root
+    anchors: Vec<DnsRecord>,
+    keys: RwLock<HashMap<String, ZoneKeys>>,
+}
+
+#[allow(clippy::redundant_field_names)]
+impl Validator {
+    pub fn new(anchors: Vec<DnsRecord>) -> Validator {
+        Validator {
+            anchors: anchors,
+            keys: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn root_anchors() -> Vec<DnsRecord> {
+        ROOT_ANCHORS.iter()
+            .map(|&(key_tag, algorithm, digest_type, digest)| DnsRecord::DS {
+                domain: String::new(),
+                key_tag: key_tag,
+                algorithm: algorithm,
+                digest_type: digest_type,
+                digest: decode_hex(digest).unwrap(),
+                ttl: 0,
+            })
+            .collect()
+    }
+
+    fn cached_keys(&self, zone: &str) -> Option<Option<Vec<DnsRecord>>> {
+        match self.keys.read().unwrap().get(zone) {
+            Some(entry) if entry.expires > Instant::now() => Some(entry.keys.clone()),
+            _ => None,
+        }
+    }
+
+    fn store_keys(&self, zone: &str, keys: Option<Vec<DnsRecord>>, ttl: u32) {
+        let now = Instant::now();
+        let mut cache = self.keys.write().unwrap();
+        cache.retain(|_, entry| entry.expires > now);
+        cache.insert(zone.to_string(), ZoneKeys {
+            keys: keys,
+            expires: now + Duration::from_secs(cmp::min(ttl, MAX_KEY_TTL) as u64),
+        });
+    }
+
+    // Verified DNSKEYs of a zone, or None when the zone is not signed
+    pub fn zone_keys<F>(&self, zone: &str, now: u32, fetch: &mut F) -> Result<Option<Vec<DnsRecord>>, Error>
+        where F: FnMut(&str, QueryType) -> Result<DnsPacket, Error> {
+        let zone = zone.trim_end_matches('.').to_lowercase();
+        if let Some(keys) = self.cached_keys(&zone) {
+            return Ok(keys); }
+
+        // DS records come from the trust anchors or else from the parent,
+        // which leaves the zone unsigned if it is unsigned itself or if it
+        // proves there are no DS records for the zone
+        let anchored = self.anchors.iter()
+            .filter(|ds| ds.get_domain().eq_ignore_ascii_case(&zone))
+            .cloned()
+            .collect::<Vec<DnsRecord>>();
+        let ds_set = if !anchored.is_empty() {
+            anchored
+        } else if zone.is_empty() {
+            return Err(bogus(EdeCode::DNSSEC_INDETERMINATE, "No trust anchor for the root".to_string()))
+        } else {
+            // Responses to DS lookups carry AD when they are secure, and are
+            // otherwise insecure unless they have some other rcode
+            let response = fetch(&zone, QueryType::DS)?;
+            let rescode = response.header.rescode;
+            if rescode != ResultCode::NOERROR && rescode != ResultCode::NXDOMAIN {
+                return Err(bogus(EdeCode::DNSSEC_INDETERMINATE, format!(
+                    "DS lookup for {} answered {:?}", zone, rescode))) }
+
+            let ds_set = response.get_answers_for(&zone, QueryType::DS);
+            if !response.header.authed_data {
+                self.store_keys(&zone, None, MAX_KEY_TTL);
+                return Ok(None); }
+
+            // A signed parent has to prove there is no DS, a zone it shows
+            // not to exist at all can not be unsigned either
+            if ds_set.is_empty() {
+                if response.header.rescode != ResultCode::NOERROR || !response.answers.is_empty() {
+                    return Err(bogus(EdeCode::DNSSEC_BOGUS, format!(
+                        "Parent of {} does not show it as an unsigned delegation", zone))) }
+                self.store_keys(&zone, None, MAX_KEY_TTL);
+                return Ok(None); }
+            ds_set
+        };
+
+        let ds_set = ds_set.into_iter()
+            .filter(supported_ds)
+            .collect::<Vec<DnsRecord>>();
+        if ds_set.is_empty() {
+            println!("No supported DS algorithm for {}, treating it as unsigned", zone);
+            self.store_keys(&zone, None, MAX_KEY_TTL);
+            return Ok(None); }
+
+        let response = fetch(&zone, QueryType::DNSKEY)?;
+        let dnskeys = response.get_answers_for(&zone, QueryType::DNSKEY);
+        let rrsigs = signatures_for(&response.answers, &zone, QueryType::DNSKEY);
+
+        // The keys the DS records point at have to sign the whole key set
+        let mut entry_keys = Vec::new();
+        for key in &dnskeys {
+            for ds in &ds_set {
+                if ds_matches(ds, key)? {
+                    entry_keys.push(key.clone());
+                    break; }
+            }
+        }
+        if entry_keys.is_empty() {
+            return Err(bogus(EdeCode::DNSKEY_MISSING, format!(
+                "No DNSKEY of {} matches its DS records", zone))) }
+
+        verify_rrset(&dnskeys, &rrsigs, &entry_keys, now)?;
+
+        let ttl = dnskeys.iter().map(|key| key.get_ttl()).min().unwrap_or(0);
+        self.store_keys(&zone, Some(dnskeys.clone()), ttl);
+        Ok(Some(dnskeys))
+    }
+
+    // Checks a signed RRset, or that the zone is unsigned if it is not
+    fn check_rrset<F>(&self, rrset: &[DnsRecord], section: &[DnsRecord], zone: &str,
+                      now: u32, fetch: &mut F) -> Result<Option<Option<String>>, Error>
+        where F: FnMut(&str, QueryType) -> Result<DnsPacket, Error> {
+        let owner = rrset[0].get_domain();
+        let qtype = rrset[0].get_querytype();
+
+        let rrsigs = signatures_for(section, owner, qtype);
+        let signer = match rrsigs.first() {
+            Some(DnsRecord::RRSIG { signer, .. }) => signer.clone(),
+            _ => {
+                // Fine in an unsigned zone, anywhere else they were stripped
+                return match self.zone_keys(zone, now, fetch)? {
+                    Some(_) => Err(bogus(EdeCode::RRSIGS_MISSING, format!(
+                        "No RRSIG for {:?} {}", qtype, owner))),
+                    None => Ok(None),
+                };
+            },
+        };
+        if !in_zone(owner, &signer) {
+            return Err(bogus(EdeCode::DNSSEC_BOGUS, format!(
+                "RRSIG for {:?} {} is signed by {}, which is not above it", qtype, owner, signer))) }
+
+        // Whether the data may go unsigned is up to the zone which answered,
+        // a signer naming some unsigned name below it must not decide that
+        let same_zone = signer.trim_end_matches('.').eq_ignore_ascii_case(zone.trim_end_matches('.'));
+        if !same_zone && !in_zone(&signer, zone) {
+            return Err(bogus(EdeCode::DNSSEC_BOGUS, format!(
+                "RRSIG for {:?} {} is signed by {}, outside of {}", qtype, owner, signer, zone))) }
+
+        let keys = match self.zone_keys(&signer, now, fetch)? {
+            Some(keys) => keys,
+            None if same_zone => return Ok(None),
+            // A child zone served by the same servers has to be signed itself
+            None => return Err(bogus(EdeCode::DNSSEC_BOGUS, format!(
+                "RRSIG for {:?} {} is signed by {}, which is not a signed zone", qtype, owner, signer))),
+        };
+
+        Ok(Some(verify_rrset(rrset, &rrsigs, &keys, now)?))
+    }
+
+    // Verified NSEC and NSEC3 records of the authority section, or None
+    // when the zone is unsigned
+    fn check_denial<F>(&self, response: &DnsPacket, zone: &str, now: u32,
+                       fetch: &mut F) -> Result<Option<Vec<DnsRecord>>, Error>
+        where F: FnMut(&str, QueryType) -> Result<DnsPacket, Error> {
+        let mut records = Vec::new();
+
+        for rrset in rrsets(&response.authorities) {
+            let qtype = rrset[0].get_querytype();
+            if qtype != QueryType::NSEC && qtype != QueryType::NSEC3 {
+                continue; }
+
+            match self.check_rrset(&rrset, &response.authorities, zone, now, fetch)? {
+                Some(_) => records.extend(rrset),
+                None => return Ok(None),
+            }
+        }
+
+        if records.iter().any(|rec| match nsec3_params(rec) {
+            Some((_, iterations, ..)) => iterations > MAX_NSEC3_ITERATIONS,
+            None => false,
+        }) {
+            return Err(bogus(EdeCode::DNSSEC_BOGUS, format!(
+                "NSEC3 iterations in {} above {}", zone, MAX_NSEC3_ITERATIONS))) }
+
+        if records.is_empty() {
+            return match self.zone_keys(zone, now, fetch)? {
+                Some(_) => Err(bogus(EdeCode::NSEC_MISSING, format!(
+                    "No NSEC or NSEC3 records from {}", zone))),
+                None => Ok(None),
+            };
+        }
+
+        Ok(Some(records))
+    }
+
+    // Validates a final response from the servers of zone. Data which
+    // should have been signed but fails to check out is an error carrying
+    // the reason as an EDE
+    pub fn validate<F>(&self, qname: &str, qtype: QueryType, response: &DnsPacket, zone: &str,
+                       now: u32, fetch: &mut F) -> Result<Security, Error>
+        where F: FnMut(&str, QueryType) -> Result<DnsPacket, Error> {
+        let rescode = response.header.rescode;
+        if rescode != ResultCode::NOERROR && rescode != ResultCode::NXDOMAIN {
+            return Ok(Security::INDETERMINATE); }
+
+        let mut secure = true;
+        for rrset in rrsets(&response.answers) {
+            let owner = rrset[0].get_domain();
+
+            // CNAMEs synthesized from a DNAME are not signed, the DNAME is.
+            // Any other alias has to be signed on its own
+            if rrset[0].get_querytype() == QueryType::CNAME {
+                let synthesized = response.get_dname_for(owner)
+                    .and_then(|dname| dname.dname_substitute(owner));
+                if let Some(target) = synthesized {
+                    if rrset.iter().all(|rec| match *rec {
+                        DnsRecord::CNAME { ref host, .. } => host.eq_ignore_ascii_case(&target),
+                        _ => false,
+                    }) {
+                        continue; }
+                }
+            }
+
+            match self.check_rrset(&rrset, &response.answers, zone, now, fetch)? {
+                None => secure = false,
+                Some(None) => {},
+                // Expanded from a wildcard, the name itself must not exist
+                Some(Some(encloser)) => {
+                    let proven = match self.check_denial(response, zone, now, fetch)? {
+                        Some(records) => any_covers(&records, owner)? || {
+                            let next_closer = last_labels(owner, label_count(&encloser) + 1);
+                            any_covers(&records, &next_closer)?
+                        },
+                        None => false,
+                    };
+                    if !proven {
+                        return Err(bogus(EdeCode::NSEC_MISSING, format!(
+                            "Wildcard answer for {} without proof it does not exist", owner))) }
+                },
+            }
+        }
+
+        // Answers of the asked for type need no further proof
+        let target = response.get_chain_target(qname);
+        if rescode == ResultCode::NOERROR &&
+            (!response.get_answers_for(&target, qtype).is_empty() || qtype == QueryType::CNAME) {
+            return Ok(security(secure)); }
+
+        // An alias leading out of the zone is followed by a separate lookup,
+        // which validates whatever is found at the end of it
+        if rescode == ResultCode::NOERROR && target != qname.to_lowercase() && !in_zone(&target, zone) {
+            return Ok(security(secure)); }
+
+        let records = match self.check_denial(response, zone, now, fetch)? {
+            Some(records) => records,
+            None => return Ok(Security::INSECURE),
+        };
+
+        let proven = if rescode == ResultCode::NXDOMAIN {
+            proves_nxdomain(&records, &target)?
+        } else {
+            proves_nodata(&records, &target, qtype)?
+        };
+        if !proven {
+            return Err(bogus(EdeCode::NSEC_MISSING, format!(
+                "No proof {:?} {} does not exist", qtype, target))) }
+
+        Ok(security(secure))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Zones signed by tests/fixtures/gen_dnssec.py, with signatures valid
+    // from 1700000000 to 1900000000
+    const FIXTURES: &str = include_str!("../tests/fixtures/dnssec.txt");
+    const NOW: u32 = 1800000000;
+
+    struct Fixtures {
+        validator: Validator,
+        packets: HashMap<String, DnsPacket>,
+    }
+
+    #[allow(clippy::redundant_field_names)]
+    impl Fixtures {
+        fn load() -> Fixtures {
+            let mut lines = FIXTURES.lines();
+            let anchors = parse_anchors(lines.next().unwrap()).unwrap();
+
+            let mut packets = HashMap::new();
+            for line in lines {
+                let (key, hex) = line.split_once(' ').unwrap();
+                let data = decode_hex(hex).unwrap();
+                let packet = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&data)).unwrap();
+                packets.insert(key.to_string(), packet);
+            }
+
+            Fixtures {
+                validator: Validator::new(anchors),
+                packets: packets,
+            }
+        }
+
+        // Stands in for the resolver: DS records come from the parent zone
+        // and are validated against it before being handed out
+        fn fetch(&self, name: &str, qtype: QueryType, now: u32) -> Result<DnsPacket, Error> {
+            let key = format!("{}|{:?}", name, qtype);
+            let mut response = match self.packets.get(&key) {
+                Some(packet) => packet.clone(),
+                None => return Err(Error::new(InvalidInput, format!("No fixture {}", key))),
+            };
+
+            if qtype == QueryType::DS {
+                let parent = name.find('.').map_or("", |idx| &name[idx+1..]);
+                let mut fetch = |name: &str, qtype: QueryType| self.fetch(name, qtype, now);
+                response.header.authed_data = self.validator.validate(
+                    name, qtype, &response, parent, now, &mut fetch)? == Security::SECURE;
+            }
+
+            Ok(response)
+        }
+
+        fn validate(&self, case: &str, zone: &str, now: u32) -> Result<Security, Error> {
+            let response = &self.packets[case];
+            let question = &response.questions[0];
+            let mut fetch = |name: &str, qtype: QueryType| self.fetch(name, qtype, now);
+            self.validator.validate(&question.name, question.qtype, response, zone, now, &mut fetch)
+        }
+    }
+
+    fn validate_at(case: &str, zone: &str, now: u32) -> Result<Security, Error> {
+        Fixtures::load().validate(case, zone, now)
+    }
+
+    fn validate(case: &str, zone: &str) -> Result<Security, Error> {
+        validate_at(case, zone, NOW)
+    }
+
+    fn ede(result: Result<Security, Error>) -> EdeCode {
+        let err = result.expect_err("validation should fail");
+        err.get_ref()
+            .and_then(|inner| inner.downcast_ref::<ExtendedError>())
+            .map(|ede| ede.code)
+            .expect("validation failures carry an EDE")
+    }
+
+    #[test]
+    fn signed_answers_are_secure() {
+        assert_eq!(validate("good", "test").unwrap(), Security::SECURE);
+        assert_eq!(validate("p384", "p384.test").unwrap(), Security::SECURE);
+        assert_eq!(validate("wildcard", "test").unwrap(), Security::SECURE);
+    }
+
+    #[test]
+    fn tampered_rdata_is_bogus() {
+        assert_eq!(ede(validate("forged", "test")), EdeCode::DNSSEC_BOGUS);
+    }
+
+    #[test]
+    fn signatures_are_only_valid_in_their_window() {
+        assert_eq!(ede(validate("expired", "test")), EdeCode::SIGNATURE_EXPIRED);
+        assert_eq!(ede(validate_at("good", "test", 1600000000)), EdeCode::SIGNATURE_NOT_YET_VALID);
+    }
+
+    #[test]
+    fn stripped_signatures_are_bogus() {
+        assert_eq!(ede(validate("stripped", "test")), EdeCode::RRSIGS_MISSING);
+        assert_eq!(ede(validate("nxdomain_nosig", "test")), EdeCode::NSEC_MISSING);
+        assert_eq!(ede(validate("wildcard_noproof", "test")), EdeCode::NSEC_MISSING);
+    }
+
+    #[test]
+    fn nsec_proves_nxdomain_and_nodata() {
+        assert_eq!(validate("nxdomain", "test").unwrap(), Security::SECURE);
+        assert_eq!(validate("nodata", "test").unwrap(), Security::SECURE);
+        // The NSEC lists A, so it can not prove there is none
+        assert_eq!(ede(validate("nodata_lie", "test")), EdeCode::NSEC_MISSING);
+    }
+
+    #[test]
+    fn nsec3_proves_nxdomain_and_nodata() {
+        assert_eq!(validate("nsec3_nxdomain", "nsec3").unwrap(), Security::SECURE);
+        assert_eq!(validate("nsec3_nodata", "nsec3").unwrap(), Security::SECURE);
+    }
+
+    #[test]
+    fn delegations_proven_unsigned_are_insecure() {
+        assert_eq!(validate("insecure", "insecure").unwrap(), Security::INSECURE);
+        // Covered by an opt-out NSEC3 span
+        assert_eq!(validate("unsigned", "unsigned.nsec3").unwrap(), Security::INSECURE);
+    }
+
+    #[test]
+    fn unexpected_rcodes_are_indeterminate() {
+        assert_eq!(validate("notimp", "test").unwrap(), Security::INDETERMINATE);
+        // A DS lookup answered that way proves nothing about the zone
+        assert_eq!(ede(validate("spoofed", "spoofed")), EdeCode::DNSSEC_INDETERMINATE);
+    }
+
+    #[test]
+    fn only_the_alias_a_dname_makes_goes_unsigned() {
+        assert_eq!(validate("dname", "test").unwrap(), Security::SECURE);
+        assert_eq!(ede(validate("dname_forged", "test")), EdeCode::RRSIGS_MISSING);
+    }
+
+    #[test]
+    fn names_which_are_no_delegation_are_not_unsigned() {
+        // A forged referral to www.test, which has no NS
+        assert_eq!(ede(validate("downgrade", "www.test")), EdeCode::NSEC_MISSING);
+        // The parent proves gone.test does not exist
+        assert_eq!(ede(validate("gone", "gone.test")), EdeCode::DNSSEC_BOGUS);
+    }
+
+    #[test]
+    fn signer_below_the_zone_can_not_downgrade_it() {
+        // A key of www.test signs the answer, but www.test is no zone
+        assert_eq!(ede(validate("downgrade", "test")), EdeCode::NSEC_MISSING);
+    }
+}
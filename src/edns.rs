@@ -0,0 +1,308 @@
+use super::BytePacketBuffer;
+use super::QueryType;
+use std::error;
+use std::fmt;
+use std::io::Error;
+use std::io::ErrorKind::InvalidInput;
+
+// Payload size we advertise and accept, large enough for most answers
+// without running into IP fragmentation trouble
+pub const EDNS_PAYLOAD_SIZE: u16 = 4096;
+
+// Option code of an Extended DNS Error (RFC 8914)
+pub const EDE_OPTION_CODE: u16 = 15;
+
+// Single option from the OPT rdata, kept as raw code and data
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+// The OPT pseudo-record (RFC 6891), lives in the additional section but
+// describes the message rather than a domain, so it is kept apart from
+// the other records of a packet
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EdnsOpt {
+    // Largest UDP payload the sender can reassemble, stored in the class
+    pub udp_payload_size: u16,
+
+    // Upper 8 bits of the 12 bit rcode, the rest is in the header
+    pub extended_rcode: u8,
+    pub version: u8,
+    pub dnssec_ok: bool,
+    // Remaining 15 flag bits, must be zero for version 0
+    pub z: u16,
+
+    pub options: Vec<EdnsOption>,
+}
+
+#[allow(clippy::redundant_field_names)]
+impl EdnsOpt {
+    pub fn new() -> EdnsOpt {
+        EdnsOpt {
+            udp_payload_size: EDNS_PAYLOAD_SIZE,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            z: 0,
+            options: Vec::new(),
+        }
+    }
+
+    // Checks whether the record at the current position is an OPT,
+    // without moving the buffer position
+    pub fn is_next(buffer: &mut BytePacketBuffer) -> Result<bool, Error> {
+        let start_pos = buffer.pos();
+
+        let mut domain = String::new();
+        buffer.read_qname(&mut domain)?;
+        let qtype = QueryType::from_num(buffer.read_u16()?);
+
+        buffer.seek(start_pos)?;
+        Ok(qtype == QueryType::OPT)
+    }
+
+    pub fn read(buffer: &mut BytePacketBuffer) -> Result<EdnsOpt, Error> {
+        // Owner name is always the root
+        let mut domain = String::new();
+        buffer.read_qname(&mut domain)?;
+        if !domain.is_empty() {
+            return Err(Error::new(InvalidInput, "OPT record must be owned by the root"))
+        }
+
+        let _ = buffer.read_u16()?; // Type, which is OPT
+        let udp_payload_size = buffer.read_u16()?;
+
+        // TTL field is reused for extended rcode, version and flags
+        let flags = buffer.read_u32()?;
+        let data_len = buffer.read_u16()? as usize;
+        let data_end = buffer.pos() + data_len;
+
+        let mut options = Vec::new();
+        while buffer.pos() < data_end {
+            let code = buffer.read_u16()?;
+            let len = buffer.read_u16()? as usize;
+            let pos = buffer.pos();
+            if pos + len > data_end {
+                return Err(Error::new(InvalidInput, "EDNS option exceeds record length"))
+            }
+
+            options.push(EdnsOption {
+                code: code,
+                data: buffer.get_range(pos, len)?.to_vec(),
+            });
+            buffer.step(len)?;
+        }
+
+        Ok(EdnsOpt {
+            udp_payload_size: udp_payload_size,
+            extended_rcode: ((flags >> 24) & 0xFF) as u8,
+            version: ((flags >> 16) & 0xFF) as u8,
+            dnssec_ok: (flags & (1 << 15)) > 0,
+            z: (flags & 0x7FFF) as u16,
+            options: options,
+        })
+    }
+
+    // Extended errors among the options, in the order they were sent
+    pub fn extended_errors(&self) -> Vec<ExtendedError> {
+        self.options.iter()
+            .filter_map(ExtendedError::from_option)
+            .collect()
+    }
+
+    // Size of the record once written, so room can be kept for it
+    pub fn len(&self) -> usize {
+        // Root name, type, class, ttl and rdata length
+        let fixed = 1 + 2 + 2 + 4 + 2;
+        fixed + self.options.iter().map(|o| 4 + o.data.len()).sum::<usize>()
+    }
+
+    pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<usize, Error> {
+        let start_pos = buffer.pos();
+
+        buffer.write_qname("")?;
+        buffer.write_u16(QueryType::OPT.to_num())?;
+        buffer.write_u16(self.udp_payload_size)?;
+        buffer.write_u32(
+            ((self.extended_rcode as u32) << 24) |
+            ((self.version as u32) << 16) |
+            ((self.dnssec_ok as u32) << 15) |
+            ((self.z & 0x7FFF) as u32))?;
+
+        let pos = buffer.pos();
+        buffer.write_u16(0)?;
+
+        for option in &self.options {
+            buffer.write_u16(option.code)?;
+            buffer.write_u16(option.data.len() as u16)?;
+            for b in &option.data {
+                buffer.write_u8(*b)?;
+            }
+        }
+
+        let size = buffer.pos() - (pos + 2);
+        buffer.set_u16(pos, size as u16)?;
+
+        Ok(buffer.pos() - start_pos)
+    }
+}
+
+// Info codes of Extended DNS Errors, from the IANA registry
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+pub enum EdeCode {
+    OTHER, // 0
+    UNSUPPORTED_DNSKEY_ALGORITHM, // 1
+    UNSUPPORTED_DS_DIGEST_TYPE, // 2
+    STALE_ANSWER, // 3
+    FORGED_ANSWER, // 4
+    DNSSEC_INDETERMINATE, // 5
+    DNSSEC_BOGUS, // 6
+    SIGNATURE_EXPIRED, // 7
+    SIGNATURE_NOT_YET_VALID, // 8
+    DNSKEY_MISSING, // 9
+    RRSIGS_MISSING, // 10
+    NO_ZONE_KEY_BIT_SET, // 11
+    NSEC_MISSING, // 12
+    CACHED_ERROR, // 13
+    NOT_READY, // 14
+    BLOCKED, // 15
+    CENSORED, // 16
+    FILTERED, // 17
+    PROHIBITED, // 18
+    STALE_NXDOMAIN_ANSWER, // 19
+    NOT_AUTHORITATIVE, // 20
+    NOT_SUPPORTED, // 21
+    NO_REACHABLE_AUTHORITY, // 22
+    NETWORK_ERROR, // 23
+    INVALID_DATA, // 24
+    UNKNOWN(u16),
+}
+
+impl EdeCode {
+    pub fn to_num(self) -> u16 {
+        match self {
+            EdeCode::OTHER => 0,
+            EdeCode::UNSUPPORTED_DNSKEY_ALGORITHM => 1,
+            EdeCode::UNSUPPORTED_DS_DIGEST_TYPE => 2,
+            EdeCode::STALE_ANSWER => 3,
+            EdeCode::FORGED_ANSWER => 4,
+            EdeCode::DNSSEC_INDETERMINATE => 5,
+            EdeCode::DNSSEC_BOGUS => 6,
+            EdeCode::SIGNATURE_EXPIRED => 7,
+            EdeCode::SIGNATURE_NOT_YET_VALID => 8,
+            EdeCode::DNSKEY_MISSING => 9,
+            EdeCode::RRSIGS_MISSING => 10,
+            EdeCode::NO_ZONE_KEY_BIT_SET => 11,
+            EdeCode::NSEC_MISSING => 12,
+            EdeCode::CACHED_ERROR => 13,
+            EdeCode::NOT_READY => 14,
+            EdeCode::BLOCKED => 15,
+            EdeCode::CENSORED => 16,
+            EdeCode::FILTERED => 17,
+            EdeCode::PROHIBITED => 18,
+            EdeCode::STALE_NXDOMAIN_ANSWER => 19,
+            EdeCode::NOT_AUTHORITATIVE => 20,
+            EdeCode::NOT_SUPPORTED => 21,
+            EdeCode::NO_REACHABLE_AUTHORITY => 22,
+            EdeCode::NETWORK_ERROR => 23,
+            EdeCode::INVALID_DATA => 24,
+            EdeCode::UNKNOWN(x) => x,
+        }
+    }
+
+    pub fn from_num(num: u16) -> EdeCode {
+        match num {
+            0 => EdeCode::OTHER,
+            1 => EdeCode::UNSUPPORTED_DNSKEY_ALGORITHM,
+            2 => EdeCode::UNSUPPORTED_DS_DIGEST_TYPE,
+            3 => EdeCode::STALE_ANSWER,
+            4 => EdeCode::FORGED_ANSWER,
+            5 => EdeCode::DNSSEC_INDETERMINATE,
+            6 => EdeCode::DNSSEC_BOGUS,
+            7 => EdeCode::SIGNATURE_EXPIRED,
+            8 => EdeCode::SIGNATURE_NOT_YET_VALID,
+            9 => EdeCode::DNSKEY_MISSING,
+            10 => EdeCode::RRSIGS_MISSING,
+            11 => EdeCode::NO_ZONE_KEY_BIT_SET,
+            12 => EdeCode::NSEC_MISSING,
+            13 => EdeCode::CACHED_ERROR,
+            14 => EdeCode::NOT_READY,
+            15 => EdeCode::BLOCKED,
+            16 => EdeCode::CENSORED,
+            17 => EdeCode::FILTERED,
+            18 => EdeCode::PROHIBITED,
+            19 => EdeCode::STALE_NXDOMAIN_ANSWER,
+            20 => EdeCode::NOT_AUTHORITATIVE,
+            21 => EdeCode::NOT_SUPPORTED,
+            22 => EdeCode::NO_REACHABLE_AUTHORITY,
+            23 => EdeCode::NETWORK_ERROR,
+            24 => EdeCode::INVALID_DATA,
+            _ => EdeCode::UNKNOWN(num),
+        }
+    }
+}
+
+// Says why a query failed (RFC 8914). Resolver errors carry one as their
+// inner error, so the reason makes it into the response to the client
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExtendedError {
+    pub code: EdeCode,
+    pub text: String,
+}
+
+#[allow(clippy::redundant_field_names)]
+impl ExtendedError {
+    pub fn new(code: EdeCode, text: &str) -> ExtendedError {
+        ExtendedError {
+            code: code,
+            text: text.to_string(),
+        }
+    }
+
+    // The extended error an io::Error carries, errors without one are
+    // described by their message only
+    pub fn from_error(err: &Error) -> ExtendedError {
+        match err.get_ref().and_then(|inner| inner.downcast_ref::<ExtendedError>()) {
+            Some(ede) => ede.clone(),
+            None => ExtendedError::new(EdeCode::OTHER, &err.to_string()),
+        }
+    }
+
+    pub fn from_option(option: &EdnsOption) -> Option<ExtendedError> {
+        if option.code != EDE_OPTION_CODE || option.data.len() < 2 {
+            return None; }
+
+        let code = ((option.data[0] as u16) << 8) | (option.data[1] as u16);
+        Some(ExtendedError {
+            code: EdeCode::from_num(code),
+            text: String::from_utf8_lossy(&option.data[2..]).into_owned(),
+        })
+    }
+
+    // Info code followed by the text, which is not null terminated
+    pub fn to_option(&self) -> EdnsOption {
+        let code = self.code.to_num();
+        let mut data = vec![(code >> 8) as u8, (code & 0xFF) as u8];
+        data.extend_from_slice(self.text.as_bytes());
+
+        EdnsOption {
+            code: EDE_OPTION_CODE,
+            data: data,
+        }
+    }
+}
+
+impl fmt::Display for ExtendedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.text.is_empty() {
+            write!(f, "{:?}", self.code)
+        } else {
+            write!(f, "{:?}: {}", self.code, self.text)
+        }
+    }
+}
+
+impl error::Error for ExtendedError {}
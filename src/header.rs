@@ -1,5 +1,6 @@
 use super::BytePacketBuffer;
 use super::ResultCode;
+use super::Opcode;
 use std::io::Error;
 
 #[derive(Clone, Debug)]
@@ -9,7 +10,7 @@ pub struct DnsHeader {
     pub recursion_desired: bool,
     pub truncated_message: bool,
     pub authoritative_answer: bool,
-    pub opcode: u8,
+    pub opcode: Opcode,
     pub response: bool,
 
     pub rescode: ResultCode,
@@ -24,13 +25,14 @@ pub struct DnsHeader {
     pub resource_entries: u16
 }
 
+#[allow(unused_parens)]
 impl DnsHeader {
     pub fn new() -> DnsHeader {
         DnsHeader { id: 0,
             recursion_desired: false,
             truncated_message: false,
             authoritative_answer: false,
-            opcode: 0,
+            opcode: Opcode::QUERY,
             response: false,
 
             rescode: ResultCode::NOERROR,
@@ -49,17 +51,19 @@ impl DnsHeader {
     pub fn read(&mut self, buffer: &mut BytePacketBuffer) -> Result<(), (Error)> {
         self.id = buffer.read_u16()?;
 
+        // QR, opcode, AA, TC and RD come first, then RA, Z, AD, CD and rcode
         let flags = buffer.read_u16()?;
         let a = (flags >> 8) as u8;
-        let b = (flags >> 8) as u8;
+        let b = (flags & 0xFF) as u8;
 
         self.recursion_desired = (a & (1 << 0)) > 0;
         self.truncated_message = (a & (1 << 1)) > 0;
         self.authoritative_answer = (a & (1 << 2)) > 0;
-        self.opcode = (a >> 3) & 0x0F;
+        self.opcode = Opcode::from_num((a >> 3) & 0x0F);
         self.response = (a & (1 << 7)) > 0;
 
-        self.rescode = ResultCode::from_num(b & 0x0F);
+        // Only the lower 4 bits, the packet adds the rest from EDNS
+        self.rescode = ResultCode::from_num((b & 0x0F) as u16);
         self.checking_disabled = (b & (1 << 4)) > 0;
         self.authed_data = (b & (1 << 5)) > 0;
         self.z = (b & (1 << 6)) > 0;
@@ -79,11 +83,11 @@ impl DnsHeader {
             (self.recursion_desired as u8) |
             ((self.truncated_message as u8) << 1) |
             ((self.authoritative_answer as u8) << 2) |
-            (self.opcode << 3) |
-            ((self.response as u8) << 7) as u8)?;
+            ((self.opcode.to_num() & 0x0F) << 3) |
+            ((self.response as u8) << 7))?;
 
         buffer.write_u8(
-            (self.rescode.clone() as u8) |
+            ((self.rescode.to_num() & 0x0F) as u8) |
             ((self.checking_disabled as u8) << 4) |
             ((self.authed_data as u8) << 5) |
             ((self.z as u8) << 6) |
@@ -96,4 +100,66 @@ impl DnsHeader {
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Flag bytes of header as written, and the header read back from them
+    fn flags_round_trip(header: &DnsHeader) -> ([u8; 2], DnsHeader) {
+        let mut buffer = BytePacketBuffer::with_max_size(12);
+        header.write(&mut buffer).unwrap();
+
+        let mut read = DnsHeader::new();
+        read.read(&mut BytePacketBuffer::from_bytes(&buffer.buf)).unwrap();
+        ([buffer.buf[2], buffer.buf[3]], read)
+    }
+
+    // Flag bytes with just the one change made to a new header, checking
+    // they read back to the same
+    fn flag_bytes(set: fn(&mut DnsHeader)) -> [u8; 2] {
+        let mut header = DnsHeader::new();
+        set(&mut header);
+        let (written, read) = flags_round_trip(&header);
+        assert_eq!(flags_round_trip(&read).0, written);
+        written
+    }
+
+    #[test]
+    fn each_flag_has_its_own_bit() {
+        assert_eq!(flag_bytes(|h| h.recursion_desired = true), [0x01, 0]);
+        assert_eq!(flag_bytes(|h| h.truncated_message = true), [0x02, 0]);
+        assert_eq!(flag_bytes(|h| h.authoritative_answer = true), [0x04, 0]);
+        assert_eq!(flag_bytes(|h| h.opcode = Opcode::UPDATE), [0x28, 0]);
+        assert_eq!(flag_bytes(|h| h.response = true), [0x80, 0]);
+        assert_eq!(flag_bytes(|h| h.rescode = ResultCode::REFUSED), [0, 0x05]);
+        assert_eq!(flag_bytes(|h| h.checking_disabled = true), [0, 0x10]);
+        assert_eq!(flag_bytes(|h| h.authed_data = true), [0, 0x20]);
+        assert_eq!(flag_bytes(|h| h.z = true), [0, 0x40]);
+        assert_eq!(flag_bytes(|h| h.recursion_available = true), [0, 0x80]);
+    }
+
+    #[test]
+    fn all_flags_round_trip() {
+        let mut header = DnsHeader::new();
+        header.id = 0xBEEF;
+        header.recursion_desired = true;
+        header.truncated_message = true;
+        header.authoritative_answer = true;
+        header.opcode = Opcode::UNKNOWN(15);
+        header.response = true;
+        header.rescode = ResultCode::from_num(15);
+        header.checking_disabled = true;
+        header.authed_data = true;
+        header.z = true;
+        header.recursion_available = true;
+
+        let (written, read) = flags_round_trip(&header);
+        assert_eq!(written, [0xFF, 0xFF]);
+        assert_eq!(read.id, 0xBEEF);
+        assert_eq!(read.opcode, Opcode::UNKNOWN(15));
+        assert_eq!(read.rescode, ResultCode::from_num(15));
+        assert!(read.recursion_desired && read.truncated_message && read.authoritative_answer && read.response);
+        assert!(read.checking_disabled && read.authed_data && read.z && read.recursion_available);
+    }
+}
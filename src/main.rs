@@ -1,5 +1,6 @@
 // Conventionall DNS uses UDP transport, however there are exceptions
-// UDP packets are limited to 512 bytes in size
+// UDP packets are limited to 512 bytes in size, larger responses are
+// truncated and then retried over TCP
 mod bytepacketbuffer;
 mod header;
 mod dns_packet;
@@ -7,39 +8,242 @@ mod qtype;
 mod questions;
 mod record;
 mod opcodes;
+mod cache;
+mod reverse;
+mod edns;
+mod tcp;
+mod threadpool;
+mod budget;
+mod dnssec;
 
-use bytepacketbuffer::BytePacketBuffer;
+use bytepacketbuffer::{BytePacketBuffer, UDP_MAX_SIZE, TCP_MAX_SIZE};
 use header::DnsHeader;
 use questions::DnsQuestion;
 use record::DnsRecord;
-use opcodes::ResultCode;
-use dns_packet::DnsPacket;
+use opcodes::{Opcode, ResultCode};
+use dns_packet::{DnsPacket, in_zone};
 use qtype::QueryType;
+use cache::Cache;
+use edns::{EdeCode, EdnsOpt, ExtendedError, EDNS_PAYLOAD_SIZE};
+use threadpool::ThreadPool;
+use budget::ResolutionBudget;
+use dnssec::{Security, Validator};
 
+use std::cmp;
+use std::collections::HashSet;
+use std::env;
+use std::process;
+use std::fs;
 use std::io::Error;
+use std::io::ErrorKind::{AddrInUse, InvalidData, InvalidInput, Other, TimedOut, UnexpectedEof, WouldBlock};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 //use std::fs::File;
 //use std::io::Read;
-use std::net::UdpSocket;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket, TcpListener, TcpStream};
+use rand::random;
 
-fn lookup(qname: &str, qtype: QueryType, server: (&str, u16)) -> Result<(DnsPacket), (Error)> {
-    let socket = UdpSocket::bind(("0.0.0.0", 43210))?;
+const DEFAULT_WORKERS: usize = 16;
+// TCP connections are served by workers of their own, so clients which
+// hold a connection open can not stall UDP
+const DEFAULT_TCP_WORKERS: usize = 4;
+// Jobs waiting for a free worker, per worker, anything more is dropped
+const QUEUE_PER_WORKER: usize = 4;
+// How long a TCP client may keep us waiting for its next bytes, and how
+// long its connection is kept open at most, so it can not hold a worker
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+const TCP_CONNECTION_TIMEOUT: Duration = Duration::from_secs(60);
+// Longest alias chain followed for a single query
+const MAX_CNAME_CHAIN: usize = 8;
+// Longest name in presentation form, 255 bytes on the wire
+const MAX_NAME_LEN: usize = 253;
+// How long a nameserver gets to answer, and how many times every
+// nameserver is tried before a lookup is given up on
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+const QUERY_ATTEMPTS: usize = 2;
+
+// a to m.root-servers.net, asked when nothing closer is cached
+const ROOT_SERVERS: [&str; 13] = [
+    "198.41.0.4", "170.247.170.2", "192.33.4.12", "199.7.91.13",
+    "192.203.230.10", "192.5.5.241", "192.112.36.4", "198.97.190.53",
+    "192.36.148.17", "192.58.128.30", "193.0.14.129", "199.7.83.42",
+    "202.12.27.33",
+];
+// Outbound queries stay clear of the well-known and registered ports
+const EPHEMERAL_PORT_MIN: u16 = 1024;
 
+// Query packet sent upstream, the same for UDP and TCP
+fn build_query(qname: &str, qtype: QueryType, use_edns: bool) -> DnsPacket {
     let mut packet = DnsPacket::new();
-    packet.header.id = 6666;
+    // Unpredictable, so spoofed answers are hard to get accepted
+    packet.header.id = random::<u16>();
     packet.header.questions = 1;
     packet.header.recursion_desired = true;
     packet.questions.push(DnsQuestion::new(qname.to_string(), qtype));
+    // Lets the server send answers larger than 512 bytes, with signatures
+    if use_edns {
+        let mut edns = EdnsOpt::new();
+        edns.dnssec_ok = true;
+        packet.edns = Some(edns);
+    }
 
-    let mut req_buffer = BytePacketBuffer::new();
-    packet.write(&mut req_buffer).unwrap();
+    packet
+}
 
-    socket.send_to(&req_buffer.buf[0..req_buffer.pos], server)?;
+// Binds to a random ephemeral port, which together with the random ID
+// makes forging a response a lot harder (Kaminsky-style poisoning)
+fn bind_random_port() -> Result<UdpSocket, Error> {
+    for _ in 0..10 {
+        let port = EPHEMERAL_PORT_MIN + random::<u16>() % (u16::MAX - EPHEMERAL_PORT_MIN);
+        match UdpSocket::bind(("0.0.0.0", port)) {
+            Ok(socket) => return Ok(socket),
+            Err(ref e) if e.kind() == AddrInUse => continue,
+            Err(e) => return Err(e),
+        }
+    }
 
-    let mut res_buffer = BytePacketBuffer::new();
-    socket.recv_from(&mut res_buffer.buf).unwrap();
+    // Lets the OS pick if we keep hitting ports in use
+    UdpSocket::bind(("0.0.0.0", 0))
+}
+
+#[allow(unused_parens)]
+fn lookup(qname: &str, qtype: QueryType, server: (&str, u16)) -> Result<(DnsPacket), (Error)> {
+    let response = lookup_udp(qname, qtype, server, true)?;
+
+    // A server which does not know EDNS may answer FORMERR to the OPT
+    // record alone, so it is asked once more without it (RFC 6891 6.2.2)
+    if response.header.rescode == ResultCode::FORMERR && response.edns.is_none() {
+        println!("FORMERR from {:?}, retrying without EDNS", server);
+        return lookup_udp(qname, qtype, server, false);
+    }
 
-    DnsPacket::from_buffer(&mut res_buffer)
+    Ok(response)
+}
+
+fn lookup_udp(qname: &str, qtype: QueryType, server: (&str, u16), use_edns: bool) -> Result<DnsPacket, Error> {
+    let socket = bind_random_port()?;
+    let server_addr = server.to_socket_addrs()?.next()
+        .ok_or_else(|| Error::new(InvalidInput, "Nameserver address did not resolve"))?;
+
+    let mut packet = build_query(qname, qtype, use_edns);
+
+    let mut req_buffer = BytePacketBuffer::with_max_size(UDP_MAX_SIZE);
+    packet.write(&mut req_buffer)?;
+
+    socket.send_to(&req_buffer.buf[0..req_buffer.pos], server_addr)?;
+
+    // Ignored packets do not extend the time the server has to answer
+    let deadline = Instant::now() + QUERY_TIMEOUT;
+    let response = loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(Error::new(TimedOut, "Nameserver did not respond in time")) }
+        socket.set_read_timeout(Some(deadline - now))?;
+
+        // Only the received bytes are handed to the parser
+        let mut res_data = [0; EDNS_PAYLOAD_SIZE as usize];
+        let (len, src) = match socket.recv_from(&mut res_data) {
+            Ok(x) => x,
+            Err(ref e) if e.kind() == WouldBlock || e.kind() == TimedOut => {
+                return Err(Error::new(TimedOut, "Nameserver did not respond in time"))
+            },
+            Err(e) => return Err(e),
+        };
+        if src != server_addr {
+            println!("Ignoring response from unexpected address {}", src);
+            continue; }
+
+        let mut res_buffer = BytePacketBuffer::from_bytes(&res_data[..len]);
+        let response = match DnsPacket::from_buffer(&mut res_buffer) {
+            Ok(x) => x,
+            Err(e) => {
+                println!("Ignoring malformed response from {}: {:?}", src, e);
+                continue;
+            },
+        };
+
+        if !response.is_response_to(&packet) {
+            println!("Ignoring response from {} not matching the query", src);
+            continue; }
+
+        break response;
+    };
+
+    // The answer did not fit, so it is fetched again over TCP
+    if response.header.truncated_message {
+        println!("Truncated response from {:?}, retrying over TCP", server);
+        return lookup_tcp(qname, qtype, server, use_edns);
+    }
+
+    Ok(response)
+}
+
+fn lookup_tcp(qname: &str, qtype: QueryType, server: (&str, u16), use_edns: bool) -> Result<DnsPacket, Error> {
+    let server_addr = server.to_socket_addrs()?.next()
+        .ok_or_else(|| Error::new(InvalidInput, "Nameserver address did not resolve"))?;
+    let mut stream = TcpStream::connect_timeout(&server_addr, QUERY_TIMEOUT)?;
+    stream.set_read_timeout(Some(QUERY_TIMEOUT))?;
+    stream.set_write_timeout(Some(QUERY_TIMEOUT))?;
+
+    let mut packet = build_query(qname, qtype, use_edns);
+
+    let mut req_buffer = BytePacketBuffer::with_max_size(TCP_MAX_SIZE);
+    packet.write(&mut req_buffer)?;
+
+    tcp::write_message(&mut stream, &req_buffer.buf[0..req_buffer.pos])?;
+
+    let mut res_buffer = tcp::read_message(&mut stream)?;
+    let response = DnsPacket::from_buffer(&mut res_buffer)?;
+
+    if !response.is_response_to(&packet) {
+        return Err(Error::new(InvalidData, "TCP response does not match the query")) }
+
+    Ok(response)
+}
+
+// Goes round the nameservers until one of them answers, a server which
+// times out, can not be reached or answers SERVFAIL/REFUSED is skipped
+fn lookup_with_failover(qname: &str, qtype: QueryType, servers: &[String],
+                        budget: &mut ResolutionBudget) -> Result<DnsPacket, Error> {
+    let mut last_err = Error::new(InvalidInput, "No nameservers to ask");
+    let mut code = EdeCode::NO_REACHABLE_AUTHORITY;
+
+    for _ in 0..QUERY_ATTEMPTS {
+        for ns in servers {
+            budget.spend_query()?;
+            println!("Attempting Lookup of {:?} {} with ns {}", qtype, qname, ns);
+
+            match lookup(qname, qtype, (ns.as_str(), 53)) {
+                // Lame or broken servers are skipped just like dead ones
+                Ok(ref response) if response.header.rescode == ResultCode::SERVFAIL ||
+                    response.header.rescode == ResultCode::REFUSED => {
+                    let mut reason = format!("{} answered {:?}", ns, response.header.rescode);
+                    if let Some(ref edns) = response.edns {
+                        for ede in edns.extended_errors() {
+                            reason.push_str(&format!(" ({})", ede)); }}
+
+                    println!("Lookup with ns {} failed: {}", ns, reason);
+                    last_err = Error::new(Other, reason);
+                    code = EdeCode::NO_REACHABLE_AUTHORITY;
+                },
+                Ok(response) => return Ok(response),
+                Err(e) => {
+                    println!("Lookup with ns {} failed: {:?}", ns, e);
+                    code = match e.kind() {
+                        TimedOut => EdeCode::NO_REACHABLE_AUTHORITY,
+                        _ => EdeCode::NETWORK_ERROR,
+                    };
+                    last_err = e;
+                },
+            }
+        }
+    }
+
+    // Why the last server failed is passed on to the client
+    let ede = ExtendedError::new(code, &format!("{} {:?}: {}", qname, qtype, last_err));
+    Err(Error::new(last_err.kind(), ede))
 }
 
 #[allow(dead_code)]
@@ -68,6 +272,7 @@ fn read_test(packet: DnsPacket) {
     }
 }
 
+#[allow(dead_code)]
 fn test_2() {
     // perform A query for google
     let qname = "yahoo.com";
@@ -102,58 +307,410 @@ fn test_2() {
     read_test(res_packet);
 }
 
-fn recursive_lookup(qname: &str, qtype: QueryType) -> Result<(DnsPacket), (Error)> {
-    // Starting with *a.root-servers.net*
-    let mut ns = "198.41.0.4".to_string();
+#[allow(unused_parens)]
+fn recursive_lookup(qname: &str, qtype: QueryType, cache: &Cache,
+                    validator: &Validator) -> Result<(DnsPacket), (Error)> {
+    let mut budget = ResolutionBudget::new();
+    follow_cname_chain(qname, qtype, cache, validator, &mut budget)
+}
+
+// Resolves qname and, when it turns out to be an alias (CNAME, or a name
+// below a DNAME), the name it points to, until records of the asked for
+// type are found. The aliases are put in front of the final answers so
+// the client gets the whole chain
+fn follow_cname_chain(qname: &str, qtype: QueryType, cache: &Cache, validator: &Validator,
+                      budget: &mut ResolutionBudget) -> Result<DnsPacket, Error> {
+    let mut response = budgeted_lookup(qname, qtype, cache, validator, budget)?;
+    if qtype == QueryType::CNAME || qtype == QueryType::DNAME {
+        return Ok(response); }
+
+    // Only secure if every response along the chain was
+    let mut secure = response.header.authed_data;
+
+    let mut chain: Vec<DnsRecord> = Vec::new();
+    let mut seen = HashSet::new();
+    seen.insert(qname.to_lowercase());
+    let mut name = qname.to_lowercase();
+    // Name the current response was asked for
+    let mut asked = name.clone();
 
     loop {
-        println!("Attempting Lookup of {:?} {} with ns {}", qtype, qname, ns);
+        // Servers often include (part of) the chain themselves
+        while response.get_answers_for(&name, qtype).is_empty() {
+            // A DNAME above the name redirects it, the matching CNAME is
+            // synthesized here rather than trusting one sent along with it
+            let redirect = response.get_dname_for(&name)
+                .and_then(|dname| dname.dname_substitute(&name).map(|target| (dname, target)));
+            let (cname, dname) = match redirect {
+                Some((dname, target)) => {
+                    if target.len() > MAX_NAME_LEN {
+                        return Err(Error::new(Other, format!("DNAME substitution for {} is too long", name))) }
+
+                    let cname = DnsRecord::CNAME {
+                        domain: name.clone(),
+                        host: target,
+                        ttl: dname.get_ttl(),
+                    };
+                    (cname, Some(dname))
+                },
+                None => match response.get_answers_for(&name, QueryType::CNAME).pop() {
+                    Some(x) => (x, None),
+                    None => break,
+                },
+            };
+            let target = match cname {
+                DnsRecord::CNAME { ref host, .. } => host.to_lowercase(),
+                _ => break,
+            };
+
+            if chain.len() >= MAX_CNAME_CHAIN {
+                return Err(Error::new(Other, format!("CNAME chain from {} is too long", qname))) }
+            if !seen.insert(target.clone()) {
+                return Err(Error::new(Other, format!("CNAME loop resolving {}", qname))) }
+
+            // Signatures go along for clients which asked for them, the
+            // synthesized CNAME has none of its own
+            if let Some(dname) = dname {
+                if !chain.contains(&dname) {
+                    chain.extend(response.get_signatures_for(dname.get_domain(), QueryType::DNAME));
+                    chain.push(dname); }
+            } else {
+                chain.extend(response.get_signatures_for(&name, QueryType::CNAME));
+            }
+            chain.push(cname);
+            name = target;
+        }
+
+        // Either records were found, or the response for the last name in
+        // the chain is all there is (e.g. NXDOMAIN for the target)
+        if asked == name || !response.get_answers_for(&name, qtype).is_empty() {
+            break; }
+
+        response = budgeted_lookup(&name, qtype, cache, validator, budget)?;
+        secure = secure && response.header.authed_data;
+        asked = name.clone();
+    }
+    response.header.authed_data = secure;
+
+    if chain.is_empty() {
+        return Ok(response); }
 
+    // Only the final records are kept from the last response, with the
+    // chain in front of them
+    chain.extend(response.get_answers_for(&name, qtype));
+    chain.extend(response.get_signatures_for(&name, qtype));
+    response.answers = chain;
+
+    Ok(response)
+}
+
+// Every name resolved on behalf of a query, including nameserver names,
+// goes through here so it is counted against the same budget
+fn budgeted_lookup(qname: &str, qtype: QueryType, cache: &Cache, validator: &Validator,
+                   budget: &mut ResolutionBudget) -> Result<DnsPacket, Error> {
+    if let Some(cached) = cache.lookup_packet(qname, qtype) {
+        println!("Cache hit for {:?} {}", qtype, qname);
+        return Ok(cached); }
+
+    budget.enter(qname, qtype)?;
+    let result = iterative_lookup(qname, qtype, cache, validator, budget);
+    budget.leave(qname, qtype);
+
+    result
+}
+
+// Keys and DS records the validator asks for always come from upstream,
+// it keeps its own cache of what they showed
+fn validation_lookup(qname: &str, qtype: QueryType, cache: &Cache, validator: &Validator,
+                     budget: &mut ResolutionBudget) -> Result<DnsPacket, Error> {
+    budget.enter(qname, qtype)?;
+    let result = iterative_lookup(qname, qtype, cache, validator, budget);
+    budget.leave(qname, qtype);
+
+    result
+}
+
+fn iterative_lookup(qname: &str, qtype: QueryType, cache: &Cache, validator: &Validator,
+                    budget: &mut ResolutionBudget) -> Result<DnsPacket, Error> {
+    // DS records live on the parent side of a zone cut, so their lookup
+    // starts above the zone itself
+    let start = match qtype {
+        QueryType::DS => qname.find('.').map_or("", |idx| &qname[idx+1..]),
+        _ => qname,
+    };
+
+    // Starting with the closest known delegation, or the root servers
+    let (mut zone, mut servers) = match cache.get_closest_ns(start) {
+        Some(x) => x,
+        None => (String::new(), ROOT_SERVERS.iter().map(|x| x.to_string()).collect()),
+    };
+    // Names of the zone's other nameservers, for when the ones asked fail
+    let mut spare_ns: Vec<String> = Vec::new();
+
+    loop {
         // The next step is to send a query
-        let ns_copy = ns.clone();
+        let mut response = match lookup_with_failover(qname, qtype, &servers, budget) {
+            Ok(response) => response,
+            Err(e) => {
+                if spare_ns.is_empty() {
+                    return Err(e) }
 
-        let server = (ns_copy.as_str(), 53);
-        let response = lookup(qname, qtype.clone(), server)?;
+                println!("Nameservers of {} failed, trying its others: {:?}", zone, e);
+                servers = resolve_ns(&mut spare_ns, &zone, cache, validator, budget)?;
+                continue;
+            },
+        };
+        response.filter_bailiwick(&zone);
+
+        // Final responses are validated before they are cached, bogus ones
+        // never are. DNSKEYs are checked by the validator itself when it
+        // builds the chain of trust
+        // Whatever the server claims, only our own validation counts
+        response.header.authed_data = false;
+        let referral = response.answers.is_empty() && response.header.rescode == ResultCode::NOERROR &&
+            response.get_referral_zone(qname).is_some_and(|new_zone| new_zone != zone);
+        if !referral && qtype != QueryType::DNSKEY {
+            let mut fetch = |name: &str, qtype: QueryType| validation_lookup(name, qtype, cache, validator, budget);
+            response.header.authed_data = validator.validate(
+                qname, qtype, &response, &zone, dnssec::unix_now(), &mut fetch)? == Security::SECURE;
+        }
+        cache.store_packet(&response);
 
         if !response.answers.is_empty() &&
             response.header.rescode == ResultCode::NOERROR {
                 return Ok(response.clone()); }
 
-        // NXDOMAIN (name doesnt exist)
+        // NXDOMAIN (name doesnt exist), which is about the last name of an
+        // alias chain rather than the aliases themselves (RFC 6604)
         if response.header.rescode == ResultCode::NXDOMAIN {
+            cache.store_negative(&response.get_chain_target(qname), qtype, &response);
             return Ok(response.clone()); }
 
+        // A referral has to lead further down, one to the same zone or
+        // back up the tree would otherwise send us round in circles
+        if let Some(new_zone) = response.get_referral_zone(qname) {
+            // Authoritative servers may list their own NS with an empty answer
+            if response.header.authoritative_answer && new_zone == zone {
+                return Ok(response.clone()) }
+
+            if new_zone.len() <= zone.len() || !in_zone(&new_zone, &zone) {
+                return Err(Error::new(Other, format!(
+                    "Referral loop resolving {}, {} does not lead below {}", qname, new_zone, zone)))
+            }
+
+            budget.spend_referral()?;
+            zone = new_zone;
+        }
+
         // Tries nameserver based NS & corrosponding A record
-        if let Some(new_ns) = response.get_resolved_ns(qname) {
-            ns = new_ns.clone();
+        let resolved = response.get_resolved_ns(qname);
+        if !resolved.is_empty() {
+            servers = resolved;
+            spare_ns = response.get_unresolved_ns(qname);
             continue; }
-        
+
         // If no NS, go with what the last server said
-        let new_ns_name = match response.get_unresolved_ns(qname) {
-            Some(x) => x,
-            None => return Ok(response.clone()),
-        };
+        let new_ns_names = response.get_unresolved_ns(qname);
+        if new_ns_names.is_empty() {
+            // No answers and no referral, NODATA if it came with an SOA
+            if response.header.rescode == ResultCode::NOERROR &&
+                response.answers.is_empty() {
+                    cache.store_negative(qname, qtype, &response); }
+            return Ok(response.clone())
+        }
 
-        // Rabbit hole 101
-        let recursive_response = recursive_lookup(&new_ns_name, QueryType::A).unwrap();
+        // Rabbit hole 101, the first nameserver name that resolves is used
+        spare_ns = new_ns_names;
+        servers = resolve_ns(&mut spare_ns, &zone, cache, validator, budget)?;
+    }
+}
 
-        if let Some(new_ns) = recursive_response.get_random_a() {
-            ns = new_ns.clone();
-        } else {
-            return Ok(response.clone())
+// Resolves nameserver names in turn until one of them has addresses,
+// the names left over are kept to fall back on
+fn resolve_ns(names: &mut Vec<String>, zone: &str, cache: &Cache, validator: &Validator,
+              budget: &mut ResolutionBudget) -> Result<Vec<String>, Error> {
+    let mut last_err = Error::new(Other, format!("No nameserver for {} resolved", zone));
+    while !names.is_empty() {
+        let name = names.remove(0);
+        match budgeted_lookup(&name, QueryType::A, cache, validator, budget) {
+            Ok(response) => {
+                let addrs = response.get_all_a();
+                if !addrs.is_empty() {
+                    return Ok(addrs) }
+            },
+            Err(e) => {
+                println!("Failed to resolve ns {}: {:?}", name, e);
+                last_err = e;
+            },
         }
     }
+
+    // A referral we can not follow leaves us without an answer
+    Err(last_err)
 }
 
-fn main() {
-    //let server = ("1.1.1.1", 53);
-    let socket = UdpSocket::bind(("0.0.0.0", 2053)).unwrap();
+// Resolves the PTR names of an address through its in-addr.arpa/ip6.arpa name
+fn reverse_lookup(addr: &IpAddr, cache: &Cache, validator: &Validator) -> Result<Vec<String>, Error> {
+    let qname = reverse::ip_to_arpa(addr);
+    let response = recursive_lookup(&qname, QueryType::PTR, cache, validator)?;
+
+    let mut hosts = Vec::new();
+    for rec in response.answers {
+        if let DnsRecord::PTR { ref host, .. } = rec {
+            hosts.push(host.clone()); }}
+
+    Ok(hosts)
+}
+
+// Builds the response to a client's request, shared by the UDP and TCP servers
+fn handle_query(request: &DnsPacket, cache: &Cache, validator: &Validator) -> DnsPacket {
+    // Initialises response packet
+    let mut packet = DnsPacket::new();
+    packet.header.id = request.header.id;
+    packet.header.opcode = request.header.opcode;
+    packet.header.recursion_desired = request.header.recursion_desired;
+    packet.header.recursion_available = true;
+    packet.header.response = true;
+
+    // Echoes EDNS back to clients that use it (RFC 6891)
+    if let Some(ref req_edns) = request.edns {
+        let mut edns = EdnsOpt::new();
+        edns.dnssec_ok = req_edns.dnssec_ok;
+        packet.edns = Some(edns);
+
+        // Only version 0 is known, anything newer is turned away
+        if req_edns.version > 0 {
+            packet.header.rescode = ResultCode::BADVERS;
+            return packet;
+        }
+    }
+
+    // Only standard queries are answered, not NOTIFY, UPDATE, etc.
+    if request.header.opcode != Opcode::QUERY {
+        packet.header.rescode = ResultCode::NOTIMP;
+        return packet;
+    }
+
+    // Checks is there are any questions (valid lookup)
+    if request.questions.is_empty() {
+        packet.header.rescode = ResultCode::FORMERR;
+        return packet;
+    }
+
+    let question = &request.questions[0];
+    packet.questions.push(question.clone());
+
+    println!("Received Query: {:?}", question);
+
+    match recursive_lookup(&question.name, question.qtype, cache, validator) {
+        Ok(mut result) => {
+            packet.header.rescode = result.header.rescode;
+
+            // AD only goes to clients which show they understand it (RFC 6840 5.7)
+            let dnssec_ok = request.edns.as_ref().is_some_and(|edns| edns.dnssec_ok);
+            if dnssec_ok || request.header.authed_data {
+                packet.header.authed_data = result.header.authed_data; }
+
+            // Signatures and proofs are only sent along when asked for
+            if !dnssec_ok {
+                result.strip_dnssec(question.qtype); }
+
+            for rec in result.answers {
+                println!("Answer: {:?}", rec);
+                packet.answers.push(rec);
+            }
+
+            for rec in result.authorities {
+                println!("Authority: {:?}", rec);
+                packet.authorities.push(rec);
+            }
+
+            for rec in result.resources {
+                println!("Resource: {:?}", rec);
+                packet.resources.push(rec);
+            }
+
+            // Extended errors from upstream are passed on as they are
+            if let (Some(edns), Some(res_edns)) = (packet.edns.as_mut(), result.edns.as_ref()) {
+                for ede in res_edns.extended_errors() {
+                    edns.options.push(ede.to_option()); }}
+        },
+        Err(e) => {
+            println!("Lookup of {:?} {} failed: {}", question.qtype, question.name, e);
+            packet.header.rescode = ResultCode::SERVFAIL;
+
+            // Only clients using EDNS can be told why
+            if let Some(ref mut edns) = packet.edns {
+                edns.options.push(ExtendedError::from_error(&e).to_option()); }
+        },
+    }
+
+    packet
+}
+
+// Writes a response of at most max_size bytes. Should a record not be
+// encodable, the client is told the lookup failed instead of being left
+// waiting for an answer
+fn encode_response(packet: &mut DnsPacket, max_size: usize) -> Result<BytePacketBuffer, Error> {
+    let mut buffer = BytePacketBuffer::with_max_size(max_size);
+    if let Err(e) = packet.write(&mut buffer) {
+        println!("Failed to encode response, answering SERVFAIL: {:?}", e);
+        packet.answers.clear();
+        packet.authorities.clear();
+        packet.resources.clear();
+        packet.header.rescode = ResultCode::SERVFAIL;
+
+        buffer = BytePacketBuffer::with_max_size(max_size);
+        packet.write(&mut buffer)?;
+    }
+
+    Ok(buffer)
+}
+
+// Largest response a UDP client can take, only more than 512 bytes
+// when it says so through EDNS
+fn udp_response_size(request: &DnsPacket) -> usize {
+    match request.edns {
+        Some(ref edns) => cmp::max(UDP_MAX_SIZE,
+            cmp::min(edns.udp_payload_size, EDNS_PAYLOAD_SIZE) as usize),
+        None => UDP_MAX_SIZE,
+    }
+}
 
+// Encodes and sends the response to a single UDP request
+fn respond_udp(socket: &UdpSocket, src: SocketAddr, request: &DnsPacket, cache: &Cache,
+               validator: &Validator) {
+    let mut packet = handle_query(request, cache, validator);
+
+    // Encode response and respond
+    let mut res_buffer = match encode_response(&mut packet, udp_response_size(request)) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("Failed to encode UDP packed: {:?}", e);
+            return;
+        }
+    };
+
+    let len = res_buffer.pos();
+    let data = match res_buffer.get_range(0, len) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("Failed to get response buffer: {:?}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = socket.send_to(data, src) {
+        println!("Failed to send response: {:?}", e);
+    }
+}
+
+fn serve_udp(socket: Arc<UdpSocket>, cache: Arc<Cache>, validator: Arc<Validator>, pool: Arc<ThreadPool>) {
     // Infinite loop to handle requests
     loop {
-        let mut req_buffer = BytePacketBuffer::new();
+        let mut req_data = [0; EDNS_PAYLOAD_SIZE as usize];
         // Gets data from src
-        let (_, src) = match socket.recv_from(&mut req_buffer.buf) {
+        let (len, src) = match socket.recv_from(&mut req_data) {
             Ok(x) => x,
             Err(e) => {
                 println!("Failed to read from UDP socket: {:?}", e);
@@ -162,6 +719,7 @@ fn main() {
         };
 
         // Serialises data into DNS Packet
+        let mut req_buffer = BytePacketBuffer::from_bytes(&req_data[..len]);
         let request = match DnsPacket::from_buffer(&mut req_buffer) {
             Ok(x) => x,
             Err(e) => {
@@ -170,67 +728,308 @@ fn main() {
             },
         };
 
-        // Initialises response packet
-        let mut packet = DnsPacket::new();
-        packet.header.id = request.header.id;
-        packet.header.recursion_desired = true;
-        packet.header.recursion_available = true;
-        packet.header.response = true;
+        // Resolving can take a while, so it is left to a worker
+        let socket = socket.clone();
+        let cache = cache.clone();
+        let validator = validator.clone();
+        if !pool.execute(move || respond_udp(&socket, src, &request, &cache, &validator)) {
+            println!("Dropping query from {}, all workers are busy", src); }
+    }
+}
+
+// A client may send several queries over one connection, each is
+// answered in turn until it hangs up, goes quiet or runs out of time
+fn handle_tcp_connection(stream: TcpStream, cache: &Cache, validator: &Validator) -> Result<(), Error> {
+    let mut stream = tcp::TimedStream::new(stream, TCP_IDLE_TIMEOUT, TCP_CONNECTION_TIMEOUT);
+
+    loop {
+        let mut req_buffer = match tcp::read_message(&mut stream) {
+            Ok(x) => x,
+            Err(ref e) if e.kind() == UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        let request = DnsPacket::from_buffer(&mut req_buffer)?;
+        let mut packet = handle_query(&request, cache, validator);
+
+        let res_buffer = encode_response(&mut packet, TCP_MAX_SIZE)?;
+
+        tcp::write_message(&mut stream, &res_buffer.buf[0..res_buffer.pos])?;
+    }
+}
+
+fn serve_tcp(listener: TcpListener, cache: Arc<Cache>, validator: Arc<Validator>, pool: Arc<ThreadPool>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(x) => x,
+            Err(e) => {
+                println!("Failed to accept TCP connection: {:?}", e);
+                continue;
+            },
+        };
+
+        // The stream is dropped along with the job, closing the connection
+        let peer = stream.peer_addr();
+        let cache = cache.clone();
+        let validator = validator.clone();
+        let queued = pool.execute(move || {
+            if let Err(e) = handle_tcp_connection(stream, &cache, &validator) {
+                println!("Failed to handle TCP connection: {:?}", e);
+            }
+        });
+        if !queued {
+            println!("Closing TCP connection from {:?}, all workers are busy", peer); }
+    }
+}
+
+// Number of queries (or TCP connections) handled at once, set through
+// RDNS_WORKERS and RDNS_TCP_WORKERS
+fn worker_count(var: &str, default: usize) -> usize {
+    env::var(var).ok()
+        .and_then(|x| x.parse::<usize>().ok())
+        .filter(|x| *x > 0)
+        .unwrap_or(default)
+}
+
+// DS records the chain of trust starts from, read from the file named by
+// RDNS_TRUST_ANCHORS or else the built in root KSKs
+fn trust_anchors() -> Vec<DnsRecord> {
+    let path = match env::var("RDNS_TRUST_ANCHORS") {
+        Ok(x) => x,
+        Err(_) => return Validator::root_anchors(),
+    };
+
+    let anchors = fs::read_to_string(&path)
+        .and_then(|text| dnssec::parse_anchors(&text))
+        .unwrap_or_else(|e| panic!("Failed to read trust anchors from {}: {}", path, e));
+    if anchors.is_empty() {
+        panic!("No trust anchors in {}", path); }
+
+    anchors
+}
+
+// `rdns -x <address>` looks up the names of an address and exits, the
+// address may also be given as its in-addr.arpa/ip6.arpa name
+fn reverse_cli(arg: &str) {
+    let addr = match arg.parse::<IpAddr>().ok().or_else(|| reverse::arpa_to_ip(arg)) {
+        Some(x) => x,
+        None => {
+            eprintln!("Not an address: {}", arg);
+            process::exit(1);
+        },
+    };
+
+    let cache = Cache::new();
+    let validator = Validator::new(trust_anchors());
+    match reverse_lookup(&addr, &cache, &validator) {
+        Ok(ref hosts) if hosts.is_empty() => println!("{} has no PTR records", addr),
+        Ok(hosts) => for host in hosts {
+            println!("{} -> {}", addr, host); },
+        Err(e) => {
+            eprintln!("Reverse lookup of {} failed: {}", addr, e);
+            process::exit(1);
+        },
+    }
+}
+
+fn main() {
+    let args = env::args().collect::<Vec<String>>();
+    if args.len() == 3 && args[1] == "-x" {
+        return reverse_cli(&args[2]); }
 
-        // Checks is there are any questions (valid lookup)
-        if request.questions.is_empty() {
-            packet.header.rescode = ResultCode::FORMERR;
-        } else {
-            let question = &request.questions[0];
-            println!("Received Query: {:?}", question);
-            if let Ok(result) = recursive_lookup(&question.name, question.qtype) {
-                packet.questions.push(question.clone());
-                packet.header.rescode = result.header.rescode;
-
-                for rec in result.answers {
-                    println!("Answer: {:?}", rec);
-                    packet.answers.push(rec);
-                }
-
-                for rec in result.authorities {
-                    println!("Authority: {:?}", rec);
-                    packet.authorities.push(rec);
-                }
-
-                for rec in result.resources {
-                    println!("Resource: {:?}", rec);
-                    packet.resources.push(rec);
-                }
+    //let server = ("1.1.1.1", 53);
+    let socket = Arc::new(UdpSocket::bind(("0.0.0.0", 2053)).unwrap());
+    let listener = TcpListener::bind(("0.0.0.0", 2053)).unwrap();
+    let cache = Arc::new(Cache::new());
+    let validator = Arc::new(Validator::new(trust_anchors()));
+
+    let workers = worker_count("RDNS_WORKERS", DEFAULT_WORKERS);
+    let tcp_workers = worker_count("RDNS_TCP_WORKERS", DEFAULT_TCP_WORKERS);
+    println!("Starting with {} workers and {} TCP workers", workers, tcp_workers);
+    let pool = Arc::new(ThreadPool::new(workers, workers * QUEUE_PER_WORKER));
+    let tcp_pool = Arc::new(ThreadPool::new(tcp_workers, tcp_workers * QUEUE_PER_WORKER));
+
+    // TCP is served next to UDP, sharing the same cache
+    let tcp_cache = cache.clone();
+    let tcp_validator = validator.clone();
+    thread::spawn(move || serve_tcp(listener, tcp_cache, tcp_validator, tcp_pool));
+
+    serve_udp(socket, cache, validator, pool);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    // Nameserver on localhost answering each query with respond, returns
+    // its port and the queries it got
+    fn fake_server<F>(count: usize, respond: F) -> (u16, thread::JoinHandle<Vec<DnsPacket>>)
+        where F: Fn(&DnsPacket) -> DnsPacket + Send + 'static {
+        let socket = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let port = socket.local_addr().unwrap().port();
+
+        let handle = thread::spawn(move || {
+            let mut queries = Vec::new();
+            for _ in 0..count {
+                let mut data = [0; 4096];
+                let (len, src) = socket.recv_from(&mut data).unwrap();
+                let query = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&data[..len])).unwrap();
+
+                let mut response = respond(&query);
+                response.header.id = query.header.id;
+                response.header.response = true;
+                response.questions = query.questions.clone();
+                let mut buffer = BytePacketBuffer::with_max_size(UDP_MAX_SIZE);
+                response.write(&mut buffer).unwrap();
+                socket.send_to(&buffer.buf[..buffer.pos()], src).unwrap();
+                queries.push(query);
+            }
+            queries
+        });
+
+        (port, handle)
+    }
+
+    #[test]
+    fn retries_without_edns_on_formerr() {
+        let (port, handle) = fake_server(2, |query| {
+            let mut response = DnsPacket::new();
+            if query.edns.is_some() {
+                response.header.rescode = ResultCode::FORMERR;
             } else {
-                packet.header.rescode = ResultCode::SERVFAIL;
+                response.answers.push(DnsRecord::A {
+                    domain: "example.com".to_string(),
+                    addr: Ipv4Addr::new(192, 0, 2, 1),
+                    ttl: 300,
+                });
             }
+            response
+        });
 
-            // Encode response and respond
-            let mut res_buffer = BytePacketBuffer::new();
-            match packet.write(&mut res_buffer) {
-                Ok(_) => {},
-                Err(e) => {
-                    println!("Failed to encode UDP packed: {:?}", e);
-                    continue;
-                }
-            };
+        let response = lookup("example.com", QueryType::A, ("127.0.0.1", port)).unwrap();
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert_eq!(response.answers.len(), 1);
 
-            let len = res_buffer.pos();
-            let data = match res_buffer.get_range(0, len) {
-                Ok(x) => x,
-                Err(e) => {
-                    println!("Failed to get response buffer: {:?}", e);
-                    continue;
-                }
-            };
+        let queries = handle.join().unwrap();
+        assert!(queries[0].edns.is_some());
+        assert!(queries[1].edns.is_none());
+    }
 
-            match socket.send_to(data, src) {
-                Ok(_) => {},
-                Err(e) => {
-                    println!("Failed to send response: {:?}", e);
-                    continue;
-                }
-            };
+    #[test]
+    fn keeps_formerr_from_edns_aware_servers() {
+        // A server which echoes OPT knows EDNS, so its FORMERR stands
+        let (port, handle) = fake_server(1, |_| {
+            let mut response = DnsPacket::new();
+            response.header.rescode = ResultCode::FORMERR;
+            response.edns = Some(EdnsOpt::new());
+            response
+        });
+
+        let response = lookup("example.com", QueryType::A, ("127.0.0.1", port)).unwrap();
+        assert_eq!(response.header.rescode, ResultCode::FORMERR);
+        assert_eq!(handle.join().unwrap().len(), 1);
+    }
+
+    // Cache holding an alias from each name to the next, so chains are
+    // followed without asking upstream
+    fn cached_chain(names: &[&str]) -> Cache {
+        let cache = Cache::new();
+        let records = names.windows(2).map(|pair| DnsRecord::CNAME {
+            domain: pair[0].to_string(),
+            host: pair[1].to_string(),
+            ttl: 300,
+        }).collect::<Vec<DnsRecord>>();
+        cache.store(&records, false);
+        cache
+    }
+
+    fn chain_lookup(qname: &str, cache: &Cache) -> Result<DnsPacket, Error> {
+        let validator = Validator::new(Vec::new());
+        follow_cname_chain(qname, QueryType::A, cache, &validator, &mut ResolutionBudget::new())
+    }
+
+    #[test]
+    fn cname_chains_are_assembled_in_order() {
+        let cache = cached_chain(&["a.example.com", "B.example.net", "c.example.org"]);
+        cache.store(&[DnsRecord::A {
+            domain: "c.example.org".to_string(),
+            addr: Ipv4Addr::new(192, 0, 2, 1),
+            ttl: 300,
+        }], false);
+
+        let response = chain_lookup("A.example.com", &cache).unwrap();
+        let owners = response.answers.iter()
+            .map(|rec| (rec.get_querytype(), rec.get_domain().to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(owners, vec![
+            (QueryType::CNAME, "a.example.com".to_string()),
+            (QueryType::CNAME, "B.example.net".to_string()),
+            (QueryType::A, "c.example.org".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn cname_loops_are_detected() {
+        let cache = cached_chain(&["a.example.com", "b.example.com", "A.example.com"]);
+        let err = chain_lookup("a.example.com", &cache).unwrap_err();
+        assert!(err.to_string().contains("loop"));
+    }
+
+    #[test]
+    fn cname_chains_are_limited() {
+        let names = (0..MAX_CNAME_CHAIN + 2).map(|n| format!("a{}.example.com", n)).collect::<Vec<_>>();
+        let names = names.iter().map(|name| name.as_str()).collect::<Vec<_>>();
+
+        let cache = cached_chain(&names);
+        let err = chain_lookup("a0.example.com", &cache).unwrap_err();
+        assert!(err.to_string().contains("too long"));
+
+        // One link less ends in an answer
+        let cache = cached_chain(&names[..MAX_CNAME_CHAIN + 1]);
+        let last = names[MAX_CNAME_CHAIN];
+        cache.store(&[DnsRecord::A {
+            domain: last.to_string(),
+            addr: Ipv4Addr::new(192, 0, 2, 1),
+            ttl: 300,
+        }], false);
+        let response = chain_lookup("a0.example.com", &cache).unwrap();
+        assert_eq!(response.answers.len(), MAX_CNAME_CHAIN + 1);
+    }
+
+    #[test]
+    fn only_standard_queries_are_answered() {
+        let cache = Cache::new();
+        let validator = Validator::new(Vec::new());
+
+        for opcode in [Opcode::IQUERY, Opcode::STATUS, Opcode::NOTIFY, Opcode::UPDATE, Opcode::UNKNOWN(15)].iter() {
+            let mut request = DnsPacket::new();
+            request.header.id = 4242;
+            request.header.opcode = *opcode;
+            request.questions.push(DnsQuestion::new("example.com".to_string(), QueryType::A));
+
+            let response = handle_query(&request, &cache, &validator);
+            assert_eq!(response.header.rescode, ResultCode::NOTIMP);
+            assert_eq!(response.header.opcode, *opcode);
+            assert_eq!(response.header.id, 4242);
+            assert!(response.answers.is_empty());
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn unencodable_response_becomes_servfail() {
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+        packet.answers.push(DnsRecord::CNAME {
+            domain: "example.com".to_string(),
+            host: format!("{}.example.com", "a".repeat(70)),
+            ttl: 300,
+        });
+
+        let buffer = encode_response(&mut packet, UDP_MAX_SIZE).unwrap();
+        let response = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&buffer.buf[..buffer.pos()])).unwrap();
+        assert_eq!(response.header.rescode, ResultCode::SERVFAIL);
+        assert!(!response.header.truncated_message);
+        assert!(response.answers.is_empty());
+    }
+}
@@ -1,22 +1,116 @@
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Opcode {
+    QUERY, // 0
+    IQUERY, // 1
+    STATUS, // 2
+    NOTIFY, // 4
+    UPDATE, // 5
+    UNKNOWN(u8),
+}
+
+impl Opcode {
+    pub fn to_num(self) -> u8 {
+        match self {
+            Opcode::QUERY => 0,
+            Opcode::IQUERY => 1,
+            Opcode::STATUS => 2,
+            Opcode::NOTIFY => 4,
+            Opcode::UPDATE => 5,
+            Opcode::UNKNOWN(x) => x,
+        }
+    }
+
+    pub fn from_num(num: u8) -> Opcode {
+        match num {
+            0 => Opcode::QUERY,
+            1 => Opcode::IQUERY,
+            2 => Opcode::STATUS,
+            4 => Opcode::NOTIFY,
+            5 => Opcode::UPDATE,
+            _ => Opcode::UNKNOWN(num),
+        }
+    }
+}
+
+// Response codes from the IANA registry, values above 15 only fit in a
+// message together with the extended rcode bits of an OPT record
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum ResultCode {
-    NOERROR = 0,
-    FORMERR = 1,
-    SERVFAIL = 2,
-    NXDOMAIN = 3,
-    NOTIMP = 4,
-    REFUSED = 5,
+    NOERROR, // 0
+    FORMERR, // 1
+    SERVFAIL, // 2
+    NXDOMAIN, // 3
+    NOTIMP, // 4
+    REFUSED, // 5
+    YXDOMAIN, // 6
+    YXRRSET, // 7
+    NXRRSET, // 8
+    NOTAUTH, // 9
+    NOTZONE, // 10
+    DSOTYPENI, // 11
+    BADVERS, // 16, also BADSIG in TSIG records
+    BADKEY, // 17
+    BADTIME, // 18
+    BADMODE, // 19
+    BADNAME, // 20
+    BADALG, // 21
+    BADTRUNC, // 22
+    BADCOOKIE, // 23
+    UNKNOWN(u16),
 }
 
 impl ResultCode {
-    pub fn from_num(num: u8) -> ResultCode {
+    pub fn to_num(self) -> u16 {
+        match self {
+            ResultCode::NOERROR => 0,
+            ResultCode::FORMERR => 1,
+            ResultCode::SERVFAIL => 2,
+            ResultCode::NXDOMAIN => 3,
+            ResultCode::NOTIMP => 4,
+            ResultCode::REFUSED => 5,
+            ResultCode::YXDOMAIN => 6,
+            ResultCode::YXRRSET => 7,
+            ResultCode::NXRRSET => 8,
+            ResultCode::NOTAUTH => 9,
+            ResultCode::NOTZONE => 10,
+            ResultCode::DSOTYPENI => 11,
+            ResultCode::BADVERS => 16,
+            ResultCode::BADKEY => 17,
+            ResultCode::BADTIME => 18,
+            ResultCode::BADMODE => 19,
+            ResultCode::BADNAME => 20,
+            ResultCode::BADALG => 21,
+            ResultCode::BADTRUNC => 22,
+            ResultCode::BADCOOKIE => 23,
+            ResultCode::UNKNOWN(x) => x,
+        }
+    }
+
+    pub fn from_num(num: u16) -> ResultCode {
         match num {
+            0 => ResultCode::NOERROR,
             1 => ResultCode::FORMERR,
             2 => ResultCode::SERVFAIL,
             3 => ResultCode::NXDOMAIN,
             4 => ResultCode::NOTIMP,
             5 => ResultCode::REFUSED,
-            0 | _ => ResultCode::NOERROR,
+            6 => ResultCode::YXDOMAIN,
+            7 => ResultCode::YXRRSET,
+            8 => ResultCode::NXRRSET,
+            9 => ResultCode::NOTAUTH,
+            10 => ResultCode::NOTZONE,
+            11 => ResultCode::DSOTYPENI,
+            16 => ResultCode::BADVERS,
+            17 => ResultCode::BADKEY,
+            18 => ResultCode::BADTIME,
+            19 => ResultCode::BADMODE,
+            20 => ResultCode::BADNAME,
+            21 => ResultCode::BADALG,
+            22 => ResultCode::BADTRUNC,
+            23 => ResultCode::BADCOOKIE,
+            _ => ResultCode::UNKNOWN(num),
         }
     }
-}
\ No newline at end of file
+}
@@ -1,23 +1,48 @@
-#[derive(PartialEq, Eq, Debug, Clone, Hash, Copy)]
+#[derive(PartialEq, Eq, Debug, Clone, Hash, Copy, PartialOrd, Ord)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum QueryType{
     UNKNOWN(u16),
     A, // 1
     NS, // 2
     CNAME, // 5
+    SOA, // 6
+    PTR, // 12
     MX, // 15
+    TXT, // 16
     AAAA, // 28
+    SRV, // 33
+    DNAME, // 39
+    OPT, // 41
+    DS, // 43
+    RRSIG, // 46
+    NSEC, // 47
+    DNSKEY, // 48
+    NSEC3, // 50
+    NSEC3PARAM, // 51
 }
 
 impl QueryType {
     #[allow(dead_code)]
-    pub fn to_num(&self) -> u16 {
-        match *self {
+    pub fn to_num(self) -> u16 {
+        match self {
             QueryType::UNKNOWN(x) => x,
             QueryType::A => 1,
             QueryType::NS => 2,
             QueryType::CNAME => 5,
+            QueryType::SOA => 6,
+            QueryType::PTR => 12,
             QueryType::MX => 15,
+            QueryType::TXT => 16,
             QueryType::AAAA => 28,
+            QueryType::SRV => 33,
+            QueryType::DNAME => 39,
+            QueryType::OPT => 41,
+            QueryType::DS => 43,
+            QueryType::RRSIG => 46,
+            QueryType::NSEC => 47,
+            QueryType::DNSKEY => 48,
+            QueryType::NSEC3 => 50,
+            QueryType::NSEC3PARAM => 51,
         }
     }
 
@@ -26,8 +51,20 @@ impl QueryType {
             1 => QueryType::A,
             2 => QueryType::NS,
             5 => QueryType::CNAME,
+            6 => QueryType::SOA,
+            12 => QueryType::PTR,
             15 => QueryType::MX,
+            16 => QueryType::TXT,
             28 => QueryType::AAAA,
+            33 => QueryType::SRV,
+            39 => QueryType::DNAME,
+            41 => QueryType::OPT,
+            43 => QueryType::DS,
+            46 => QueryType::RRSIG,
+            47 => QueryType::NSEC,
+            48 => QueryType::DNSKEY,
+            50 => QueryType::NSEC3,
+            51 => QueryType::NSEC3PARAM,
             _ => QueryType::UNKNOWN(num),
         }
     }
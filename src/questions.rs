@@ -8,6 +8,7 @@ pub struct DnsQuestion {
     pub qtype: QueryType
 }
 
+#[allow(unused_parens, clippy::redundant_field_names)]
 impl DnsQuestion {
     pub fn new(name: String, qtype: QueryType) -> DnsQuestion {
         DnsQuestion {
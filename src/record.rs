@@ -1,16 +1,19 @@
 use super::BytePacketBuffer;
 use super::QueryType;
+use super::in_zone;
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
 use std::io::Error;
+use std::io::ErrorKind::InvalidInput;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
-#[allow(dead_code)]
+#[allow(dead_code, clippy::upper_case_acronyms)]
 pub enum DnsRecord {
     UNKNOWN { // 0
         domain: String,
         qtype: u16,
-        data_len: u16,
+        class: u16,
+        data: Vec<u8>,
         ttl: u32,
     },
     A { // 1
@@ -30,6 +33,24 @@ pub enum DnsRecord {
         host: String,
         ttl: u32
     },
+    // SOA 6
+    SOA {
+        domain: String,
+        mname: String,
+        rname: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+        ttl: u32,
+    },
+    // PTR 12
+    PTR {
+        domain: String,
+        host: String,
+        ttl: u32,
+    },
     // MX 15
     MX {
         domain: String,
@@ -37,14 +58,98 @@ pub enum DnsRecord {
         host: String,
         ttl: u32,
     },
+    // TXT 16
+    TXT {
+        domain: String,
+        // Each character-string is arbitrary binary of up to 255 bytes
+        data: Vec<Vec<u8>>,
+        ttl: u32,
+    },
     // AAAA 28
     AAAA {
         domain: String,
         addr: Ipv6Addr,
         ttl: u32,
     },
+    // SRV 33
+    SRV {
+        domain: String,
+        priority: u16,
+        weight: u16,
+        port: u16,
+        host: String,
+        ttl: u32,
+    },
+    // DNAME 39
+    DNAME {
+        domain: String,
+        host: String,
+        ttl: u32,
+    },
+    // DS 43
+    DS {
+        domain: String,
+        key_tag: u16,
+        algorithm: u8,
+        digest_type: u8,
+        digest: Vec<u8>,
+        ttl: u32,
+    },
+    // RRSIG 46
+    RRSIG {
+        domain: String,
+        type_covered: QueryType,
+        algorithm: u8,
+        labels: u8,
+        original_ttl: u32,
+        // Validity window, as seconds since the epoch (modulo 2^32)
+        expiration: u32,
+        inception: u32,
+        key_tag: u16,
+        signer: String,
+        signature: Vec<u8>,
+        ttl: u32,
+    },
+    // NSEC 47
+    NSEC {
+        domain: String,
+        next_domain: String,
+        types: Vec<QueryType>,
+        ttl: u32,
+    },
+    // DNSKEY 48
+    DNSKEY {
+        domain: String,
+        flags: u16,
+        protocol: u8,
+        algorithm: u8,
+        public_key: Vec<u8>,
+        ttl: u32,
+    },
+    // NSEC3 50
+    NSEC3 {
+        domain: String,
+        hash_algorithm: u8,
+        flags: u8,
+        iterations: u16,
+        salt: Vec<u8>,
+        // Raw hash of the next owner, not its base32 label
+        next_hashed: Vec<u8>,
+        types: Vec<QueryType>,
+        ttl: u32,
+    },
+    // NSEC3PARAM 51
+    NSEC3PARAM {
+        domain: String,
+        hash_algorithm: u8,
+        flags: u8,
+        iterations: u16,
+        salt: Vec<u8>,
+        ttl: u32,
+    },
 }
 
+#[allow(unused_parens, clippy::redundant_field_names, clippy::identity_op)]
 impl DnsRecord {
     pub fn read(buffer: &mut BytePacketBuffer) -> Result<(DnsRecord), (Error)> {
         let mut domain = String::new();
@@ -52,12 +157,16 @@ impl DnsRecord {
 
         let qtype_num = buffer.read_u16()?;
         let qtype = QueryType::from_num(qtype_num);
-        let _ = buffer.read_u16()?; // Class, which is ignored
+        let class = buffer.read_u16()?; // Class, only kept for unknown types
         let ttl = buffer.read_u32()?;
         let data_len = buffer.read_u16()?;
+        let data_start = buffer.pos();
+        let data_end = data_start + data_len as usize;
+        if data_end > buffer.buf.len() {
+            return Err(Error::new(InvalidInput, "Record data runs past the end of the packet")) }
 
         // Handles each type seperately
-        match qtype {
+        let record: Result<DnsRecord, Error> = match qtype {
             QueryType::A => {
                 let raw_addr = buffer.read_u32()?;
                 let addr = Ipv4Addr::new(
@@ -113,6 +222,40 @@ impl DnsRecord {
                     ttl: ttl
                 })
             },
+            QueryType::SOA => {
+                let mut mname = String::new();
+                buffer.read_qname(&mut mname)?;
+                let mut rname = String::new();
+                buffer.read_qname(&mut rname)?;
+
+                let serial = buffer.read_u32()?;
+                let refresh = buffer.read_u32()?;
+                let retry = buffer.read_u32()?;
+                let expire = buffer.read_u32()?;
+                let minimum = buffer.read_u32()?;
+
+                Ok(DnsRecord::SOA {
+                    domain: domain,
+                    mname: mname,
+                    rname: rname,
+                    serial: serial,
+                    refresh: refresh,
+                    retry: retry,
+                    expire: expire,
+                    minimum: minimum,
+                    ttl: ttl,
+                })
+            },
+            QueryType::PTR => {
+                let mut ptr = String::new();
+                buffer.read_qname(&mut ptr)?;
+
+                Ok(DnsRecord::PTR {
+                    domain: domain,
+                    host: ptr,
+                    ttl: ttl
+                })
+            },
             QueryType::MX => {
                 let priority = buffer.read_u16()?;
                 let mut mx = String::new();
@@ -125,16 +268,275 @@ impl DnsRecord {
                     ttl: ttl,
                 })
             },
-            QueryType::UNKNOWN(_) => {
-                let _ = buffer.step(data_len as usize);
+            QueryType::TXT => {
+                let mut data = Vec::new();
+
+                // One or more length prefixed strings, filling the rdata
+                while buffer.pos() < data_start + data_len as usize {
+                    let len = buffer.read()? as usize;
+                    let pos = buffer.pos();
+                    if pos + len > data_start + data_len as usize {
+                        return Err(Error::new(InvalidInput, "TXT string exceeds record length"))
+                    }
+
+                    data.push(buffer.get_range(pos, len)?.to_vec());
+                    buffer.step(len)?;
+                }
+
+                Ok(DnsRecord::TXT {
+                    domain: domain,
+                    data: data,
+                    ttl: ttl,
+                })
+            },
+            QueryType::SRV => {
+                let priority = buffer.read_u16()?;
+                let weight = buffer.read_u16()?;
+                let port = buffer.read_u16()?;
+                let mut srv = String::new();
+                buffer.read_qname(&mut srv)?;
+
+                Ok(DnsRecord::SRV {
+                    domain: domain,
+                    priority: priority,
+                    weight: weight,
+                    port: port,
+                    host: srv,
+                    ttl: ttl,
+                })
+            },
+            QueryType::DNAME => {
+                let mut dname = String::new();
+                buffer.read_qname(&mut dname)?;
+
+                Ok(DnsRecord::DNAME {
+                    domain: domain,
+                    host: dname,
+                    ttl: ttl
+                })
+            },
+            QueryType::DS => {
+                let key_tag = buffer.read_u16()?;
+                let algorithm = buffer.read()?;
+                let digest_type = buffer.read()?;
+                let digest = read_rest(buffer, data_end)?;
+
+                Ok(DnsRecord::DS {
+                    domain: domain,
+                    key_tag: key_tag,
+                    algorithm: algorithm,
+                    digest_type: digest_type,
+                    digest: digest,
+                    ttl: ttl,
+                })
+            },
+            QueryType::RRSIG => {
+                let type_covered = QueryType::from_num(buffer.read_u16()?);
+                let algorithm = buffer.read()?;
+                let labels = buffer.read()?;
+                let original_ttl = buffer.read_u32()?;
+                let expiration = buffer.read_u32()?;
+                let inception = buffer.read_u32()?;
+                let key_tag = buffer.read_u16()?;
+                let mut signer = String::new();
+                buffer.read_qname(&mut signer)?;
+                let signature = read_rest(buffer, data_end)?;
+
+                Ok(DnsRecord::RRSIG {
+                    domain: domain,
+                    type_covered: type_covered,
+                    algorithm: algorithm,
+                    labels: labels,
+                    original_ttl: original_ttl,
+                    expiration: expiration,
+                    inception: inception,
+                    key_tag: key_tag,
+                    signer: signer,
+                    signature: signature,
+                    ttl: ttl,
+                })
+            },
+            QueryType::NSEC => {
+                let mut next_domain = String::new();
+                buffer.read_qname(&mut next_domain)?;
+                let types = read_type_bitmap(buffer, data_end)?;
+
+                Ok(DnsRecord::NSEC {
+                    domain: domain,
+                    next_domain: next_domain,
+                    types: types,
+                    ttl: ttl,
+                })
+            },
+            QueryType::DNSKEY => {
+                let flags = buffer.read_u16()?;
+                let protocol = buffer.read()?;
+                let algorithm = buffer.read()?;
+                let public_key = read_rest(buffer, data_end)?;
+
+                Ok(DnsRecord::DNSKEY {
+                    domain: domain,
+                    flags: flags,
+                    protocol: protocol,
+                    algorithm: algorithm,
+                    public_key: public_key,
+                    ttl: ttl,
+                })
+            },
+            QueryType::NSEC3 => {
+                let hash_algorithm = buffer.read()?;
+                let flags = buffer.read()?;
+                let iterations = buffer.read_u16()?;
+                let salt = read_sized(buffer, data_end)?;
+                let next_hashed = read_sized(buffer, data_end)?;
+                let types = read_type_bitmap(buffer, data_end)?;
+
+                Ok(DnsRecord::NSEC3 {
+                    domain: domain,
+                    hash_algorithm: hash_algorithm,
+                    flags: flags,
+                    iterations: iterations,
+                    salt: salt,
+                    next_hashed: next_hashed,
+                    types: types,
+                    ttl: ttl,
+                })
+            },
+            QueryType::NSEC3PARAM => {
+                let hash_algorithm = buffer.read()?;
+                let flags = buffer.read()?;
+                let iterations = buffer.read_u16()?;
+                let salt = read_sized(buffer, data_end)?;
+
+                Ok(DnsRecord::NSEC3PARAM {
+                    domain: domain,
+                    hash_algorithm: hash_algorithm,
+                    flags: flags,
+                    iterations: iterations,
+                    salt: salt,
+                    ttl: ttl,
+                })
+            },
+            // OPT is only valid in the additional section, where the packet
+            // picks it up itself, anywhere else it is treated as opaque
+            QueryType::OPT | QueryType::UNKNOWN(_) => {
+                // Kept byte for byte so it can be forwarded as-is (RFC 3597)
+                let data = buffer.get_range(data_start, data_len as usize)?.to_vec();
+                buffer.step(data_len as usize)?;
 
                 Ok(DnsRecord::UNKNOWN {
                     domain: domain,
                     qtype: qtype_num,
-                    data_len: data_len,
+                    class: class,
+                    data: data,
                     ttl: ttl,
                 })
             }
+        };
+
+        // The rdata must be used up exactly, anything else means the record
+        // is malformed and the rest of the packet can not be trusted
+        let record = record?;
+        if buffer.pos() != data_end {
+            return Err(Error::new(InvalidInput, "Record data does not match record length")) }
+
+        Ok(record)
+    }
+
+    pub fn get_querytype(&self) -> QueryType {
+        match *self {
+            DnsRecord::A { .. } => QueryType::A,
+            DnsRecord::NS { .. } => QueryType::NS,
+            DnsRecord::CNAME { .. } => QueryType::CNAME,
+            DnsRecord::SOA { .. } => QueryType::SOA,
+            DnsRecord::PTR { .. } => QueryType::PTR,
+            DnsRecord::MX { .. } => QueryType::MX,
+            DnsRecord::TXT { .. } => QueryType::TXT,
+            DnsRecord::AAAA { .. } => QueryType::AAAA,
+            DnsRecord::SRV { .. } => QueryType::SRV,
+            DnsRecord::DNAME { .. } => QueryType::DNAME,
+            DnsRecord::DS { .. } => QueryType::DS,
+            DnsRecord::RRSIG { .. } => QueryType::RRSIG,
+            DnsRecord::NSEC { .. } => QueryType::NSEC,
+            DnsRecord::DNSKEY { .. } => QueryType::DNSKEY,
+            DnsRecord::NSEC3 { .. } => QueryType::NSEC3,
+            DnsRecord::NSEC3PARAM { .. } => QueryType::NSEC3PARAM,
+            DnsRecord::UNKNOWN { qtype, .. } => QueryType::UNKNOWN(qtype),
+        }
+    }
+
+    // Only records of unknown types keep their class, all others are IN
+    pub fn get_class(&self) -> u16 {
+        match *self {
+            DnsRecord::UNKNOWN { class, .. } => class,
+            _ => 1,
+        }
+    }
+
+    pub fn get_domain(&self) -> &str {
+        match *self {
+            DnsRecord::A { ref domain, .. } |
+            DnsRecord::NS { ref domain, .. } |
+            DnsRecord::CNAME { ref domain, .. } |
+            DnsRecord::SOA { ref domain, .. } |
+            DnsRecord::PTR { ref domain, .. } |
+            DnsRecord::MX { ref domain, .. } |
+            DnsRecord::TXT { ref domain, .. } |
+            DnsRecord::AAAA { ref domain, .. } |
+            DnsRecord::SRV { ref domain, .. } |
+            DnsRecord::DNAME { ref domain, .. } |
+            DnsRecord::DS { ref domain, .. } |
+            DnsRecord::RRSIG { ref domain, .. } |
+            DnsRecord::NSEC { ref domain, .. } |
+            DnsRecord::DNSKEY { ref domain, .. } |
+            DnsRecord::NSEC3 { ref domain, .. } |
+            DnsRecord::NSEC3PARAM { ref domain, .. } |
+            DnsRecord::UNKNOWN { ref domain, .. } => domain,
+        }
+    }
+
+    pub fn get_ttl(&self) -> u32 {
+        match *self {
+            DnsRecord::A { ttl, .. } |
+            DnsRecord::NS { ttl, .. } |
+            DnsRecord::CNAME { ttl, .. } |
+            DnsRecord::SOA { ttl, .. } |
+            DnsRecord::PTR { ttl, .. } |
+            DnsRecord::MX { ttl, .. } |
+            DnsRecord::TXT { ttl, .. } |
+            DnsRecord::AAAA { ttl, .. } |
+            DnsRecord::SRV { ttl, .. } |
+            DnsRecord::DNAME { ttl, .. } |
+            DnsRecord::DS { ttl, .. } |
+            DnsRecord::RRSIG { ttl, .. } |
+            DnsRecord::NSEC { ttl, .. } |
+            DnsRecord::DNSKEY { ttl, .. } |
+            DnsRecord::NSEC3 { ttl, .. } |
+            DnsRecord::NSEC3PARAM { ttl, .. } |
+            DnsRecord::UNKNOWN { ttl, .. } => ttl,
+        }
+    }
+
+    // Used by the cache to hand out the remaining time to live
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match *self {
+            DnsRecord::A { ref mut ttl, .. } |
+            DnsRecord::NS { ref mut ttl, .. } |
+            DnsRecord::CNAME { ref mut ttl, .. } |
+            DnsRecord::SOA { ref mut ttl, .. } |
+            DnsRecord::PTR { ref mut ttl, .. } |
+            DnsRecord::MX { ref mut ttl, .. } |
+            DnsRecord::TXT { ref mut ttl, .. } |
+            DnsRecord::AAAA { ref mut ttl, .. } |
+            DnsRecord::SRV { ref mut ttl, .. } |
+            DnsRecord::DNAME { ref mut ttl, .. } |
+            DnsRecord::DS { ref mut ttl, .. } |
+            DnsRecord::RRSIG { ref mut ttl, .. } |
+            DnsRecord::NSEC { ref mut ttl, .. } |
+            DnsRecord::DNSKEY { ref mut ttl, .. } |
+            DnsRecord::NSEC3 { ref mut ttl, .. } |
+            DnsRecord::NSEC3PARAM { ref mut ttl, .. } |
+            DnsRecord::UNKNOWN { ref mut ttl, .. } => *ttl = new_ttl,
         }
     }
 
@@ -167,7 +569,7 @@ impl DnsRecord {
                 buffer.write_qname(host)?;
 
                 let size = buffer.pos() - (pos + 2);
-                let _ = buffer.set_u16(pos, size as u16);
+                buffer.set_u16(pos, size as u16)?;
             },
             DnsRecord::CNAME { ref domain, ref host, ttl } => {
                 buffer.write_qname(domain)?;
@@ -181,7 +583,42 @@ impl DnsRecord {
                 buffer.write_qname(host)?;
 
                 let size = buffer.pos() - (pos + 2);
-                let _ = buffer.set_u16(pos, size as u16);
+                buffer.set_u16(pos, size as u16)?;
+            },
+            DnsRecord::SOA { ref domain, ref mname, ref rname, serial, refresh,
+                             retry, expire, minimum, ttl } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SOA.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(mname)?;
+                buffer.write_qname(rname)?;
+                buffer.write_u32(serial)?;
+                buffer.write_u32(refresh)?;
+                buffer.write_u32(retry)?;
+                buffer.write_u32(expire)?;
+                buffer.write_u32(minimum)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            },
+            DnsRecord::PTR { ref domain, ref host, ttl } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::PTR.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
             },
             DnsRecord::MX { ref domain, priority, ref host, ttl } => {
                 buffer.write_qname(domain)?;
@@ -196,7 +633,30 @@ impl DnsRecord {
                 buffer.write_qname(host)?;
 
                 let size = buffer.pos() - (pos + 2);
-                let _ = buffer.set_u16(pos, size as u16);
+                buffer.set_u16(pos, size as u16)?;
+            },
+            DnsRecord::TXT { ref domain, ref data, ttl } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::TXT.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                for string in data {
+                    if string.len() > 0xFF {
+                        return Err(Error::new(InvalidInput, "TXT string exceeds the 255 byte length"))
+                    }
+
+                    buffer.write_u8(string.len() as u8)?;
+                    for b in string {
+                        buffer.write_u8(*b)?;
+                    }
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
             },
             DnsRecord::AAAA { ref domain, ref addr, ttl } => {
                 buffer.write_qname(domain)?;
@@ -209,10 +669,610 @@ impl DnsRecord {
                     buffer.write_u16(*octet)?;
                 }
             },
-            DnsRecord::UNKNOWN { .. } => {
-                println!("Skipping Record: {:?}", self);
+            DnsRecord::SRV { ref domain, priority, weight, port, ref host, ttl } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SRV.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(priority)?;
+                buffer.write_u16(weight)?;
+                buffer.write_u16(port)?;
+                buffer.write_qname_uncompressed(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            },
+            DnsRecord::DNAME { ref domain, ref host, ttl } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::DNAME.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                // The target is never compressed (RFC 6672 2.5)
+                buffer.write_qname_uncompressed(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            },
+            DnsRecord::DS { ref domain, key_tag, algorithm, digest_type, ref digest, ttl } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::DS.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(key_tag)?;
+                buffer.write_u8(algorithm)?;
+                buffer.write_u8(digest_type)?;
+                for b in digest {
+                    buffer.write_u8(*b)?;
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            },
+            DnsRecord::RRSIG { ref domain, type_covered, algorithm, labels, original_ttl,
+                               expiration, inception, key_tag, ref signer, ref signature, ttl } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::RRSIG.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(type_covered.to_num())?;
+                buffer.write_u8(algorithm)?;
+                buffer.write_u8(labels)?;
+                buffer.write_u32(original_ttl)?;
+                buffer.write_u32(expiration)?;
+                buffer.write_u32(inception)?;
+                buffer.write_u16(key_tag)?;
+                // Names in DNSSEC rdata are never compressed (RFC 4034)
+                buffer.write_qname_uncompressed(signer)?;
+                for b in signature {
+                    buffer.write_u8(*b)?;
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            },
+            DnsRecord::NSEC { ref domain, ref next_domain, ref types, ttl } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::NSEC.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname_preserving_case(next_domain)?;
+                write_type_bitmap(buffer, types)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            },
+            DnsRecord::DNSKEY { ref domain, flags, protocol, algorithm, ref public_key, ttl } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::DNSKEY.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(flags)?;
+                buffer.write_u8(protocol)?;
+                buffer.write_u8(algorithm)?;
+                for b in public_key {
+                    buffer.write_u8(*b)?;
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            },
+            DnsRecord::NSEC3 { ref domain, hash_algorithm, flags, iterations, ref salt,
+                               ref next_hashed, ref types, ttl } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::NSEC3.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u8(hash_algorithm)?;
+                buffer.write_u8(flags)?;
+                buffer.write_u16(iterations)?;
+                write_sized(buffer, salt)?;
+                write_sized(buffer, next_hashed)?;
+                write_type_bitmap(buffer, types)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            },
+            DnsRecord::NSEC3PARAM { ref domain, hash_algorithm, flags, iterations, ref salt, ttl } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::NSEC3PARAM.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u8(hash_algorithm)?;
+                buffer.write_u8(flags)?;
+                buffer.write_u16(iterations)?;
+                write_sized(buffer, salt)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            },
+            DnsRecord::UNKNOWN { ref domain, qtype, class, ref data, ttl } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(qtype)?;
+                buffer.write_u16(class)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(data.len() as u16)?;
+
+                for b in data {
+                    buffer.write_u8(*b)?;
+                }
             }
         }
         Ok(buffer.pos() - start_pos)
     }
-}
\ No newline at end of file
+
+    // For a DNAME, rewrites a name below its owner to the same name below
+    // its target, e.g. www.example.com with example.com DNAME example.net
+    // becomes www.example.net (RFC 6672)
+    pub fn dname_substitute(&self, name: &str) -> Option<String> {
+        if let DnsRecord::DNAME { ref domain, ref host, .. } = *self {
+            // Only names below the owner are redirected, not the owner itself
+            if name.eq_ignore_ascii_case(domain) || !in_zone(name, domain) {
+                return None; }
+
+            // Names come off the wire, so nothing is sliced unchecked
+            let len = name.trim_end_matches('.').len().checked_sub(domain.trim_end_matches('.').len())?;
+            let prefix = name.get(..len)?.trim_end_matches('.');
+            if host.is_empty() {
+                return Some(prefix.to_string()); }
+            return Some(format!("{}.{}", prefix, host));
+        }
+
+        None
+    }
+
+    // Generic presentation of rdata from RFC 3597, e.g. "\# 4 0a000001"
+    #[allow(dead_code)]
+    pub fn to_generic_rdata(data: &[u8]) -> String {
+        let mut out = format!("\\# {}", data.len());
+        if !data.is_empty() {
+            out.push(' ');
+            for b in data {
+                out.push_str(&format!("{:02x}", b));
+            }
+        }
+        out
+    }
+
+    // Parses the generic presentation back into rdata, the hex may be
+    // split into groups by whitespace
+    #[allow(dead_code)]
+    pub fn from_generic_rdata(text: &str) -> Result<Vec<u8>, Error> {
+        let mut parts = text.split_whitespace();
+        if parts.next() != Some("\\#") {
+            return Err(Error::new(InvalidInput, "Generic rdata must start with \\#"))
+        }
+
+        let len = parts.next()
+            .and_then(|x| x.parse::<usize>().ok())
+            .ok_or_else(|| Error::new(InvalidInput, "Generic rdata is missing its length"))?;
+
+        let hex: String = parts.collect();
+        if !hex.is_ascii() || hex.len() != len * 2 {
+            return Err(Error::new(InvalidInput, "Generic rdata length does not match its data"))
+        }
+
+        let mut data = Vec::with_capacity(len);
+        for i in 0..len {
+            let byte = u8::from_str_radix(&hex[i*2..i*2+2], 16)
+                .map_err(|_| Error::new(InvalidInput, "Generic rdata contains invalid hex"))?;
+            data.push(byte);
+        }
+
+        Ok(data)
+    }
+}
+
+// Everything left of the rdata, e.g. a key, digest or signature
+fn read_rest(buffer: &mut BytePacketBuffer, end: usize) -> Result<Vec<u8>, Error> {
+    let pos = buffer.pos();
+    if pos > end {
+        return Err(Error::new(InvalidInput, "Record data exceeds record length"))
+    }
+
+    let data = buffer.get_range(pos, end - pos)?.to_vec();
+    buffer.step(end - pos)?;
+    Ok(data)
+}
+
+// Field prefixed by its length as a single byte, e.g. the NSEC3 salt
+fn read_sized(buffer: &mut BytePacketBuffer, end: usize) -> Result<Vec<u8>, Error> {
+    let len = buffer.read()? as usize;
+    let pos = buffer.pos();
+    if pos + len > end {
+        return Err(Error::new(InvalidInput, "Record data exceeds record length"))
+    }
+
+    let data = buffer.get_range(pos, len)?.to_vec();
+    buffer.step(len)?;
+    Ok(data)
+}
+
+fn write_sized(buffer: &mut BytePacketBuffer, data: &[u8]) -> Result<(), Error> {
+    if data.len() > 0xFF {
+        return Err(Error::new(InvalidInput, "Record data field exceeds the 255 byte length"))
+    }
+
+    buffer.write_u8(data.len() as u8)?;
+    for b in data {
+        buffer.write_u8(*b)?;
+    }
+    Ok(())
+}
+
+// Types present at a name, as used by NSEC and NSEC3 (RFC 4034 4.1.2).
+// Types are split into windows of 256, each a window number, a length
+// and a bitmap where the first bit of the first byte is type 0
+fn read_type_bitmap(buffer: &mut BytePacketBuffer, end: usize) -> Result<Vec<QueryType>, Error> {
+    let mut types = Vec::new();
+
+    while buffer.pos() < end {
+        let window = buffer.read()? as u16;
+        let len = buffer.read()? as usize;
+        if len == 0 || len > 32 || buffer.pos() + len > end {
+            return Err(Error::new(InvalidInput, "Invalid type bitmap window"))
+        }
+
+        for i in 0..len {
+            let bits = buffer.read()?;
+            for bit in 0..8 {
+                if bits & (0x80 >> bit) > 0 {
+                    types.push(QueryType::from_num((window << 8) | (i * 8 + bit) as u16));
+                }
+            }
+        }
+    }
+
+    Ok(types)
+}
+
+fn write_type_bitmap(buffer: &mut BytePacketBuffer, types: &[QueryType]) -> Result<(), Error> {
+    let mut nums = types.iter().map(|t| t.to_num()).collect::<Vec<u16>>();
+    nums.sort();
+    nums.dedup();
+
+    let mut i = 0;
+    while i < nums.len() {
+        let window = nums[i] >> 8;
+        let mut bits = [0u8; 32];
+        let mut len = 0;
+
+        // Only as many bytes as needed for the highest type in the window
+        while i < nums.len() && nums[i] >> 8 == window {
+            let low = (nums[i] & 0xFF) as usize;
+            bits[low / 8] |= 0x80 >> (low % 8);
+            len = low / 8 + 1;
+            i += 1;
+        }
+
+        buffer.write_u8(window as u8)?;
+        buffer.write_u8(len as u8)?;
+        for b in &bits[..len] {
+            buffer.write_u8(*b)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Owner "a", the given type and RDLENGTH, followed by the given bytes
+    fn record_bytes(qtype: u16, data_len: u16, rest: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![1, b'a', 0];
+        bytes.extend_from_slice(&qtype.to_be_bytes());
+        bytes.extend_from_slice(&[0, 1, 0, 0, 0x0E, 0x10]);
+        bytes.extend_from_slice(&data_len.to_be_bytes());
+        bytes.extend_from_slice(rest);
+        bytes
+    }
+
+    #[test]
+    fn reads_a_record_of_exact_length() {
+        let bytes = record_bytes(1, 4, &[10, 0, 0, 1]);
+        let mut buffer = BytePacketBuffer::from_bytes(&bytes);
+
+        let record = DnsRecord::read(&mut buffer).unwrap();
+        assert_eq!(record, DnsRecord::A {
+            domain: "a".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 1),
+            ttl: 3600,
+        });
+        assert_eq!(buffer.pos(), bytes.len());
+    }
+
+    #[test]
+    fn rejects_rdata_shorter_than_the_type_needs() {
+        // The address would be taken from the record which follows
+        let mut bytes = record_bytes(1, 0, &[]);
+        bytes.extend(record_bytes(1, 4, &[10, 0, 0, 1]));
+        let mut buffer = BytePacketBuffer::from_bytes(&bytes);
+
+        assert!(DnsRecord::read(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn rejects_rdata_with_trailing_bytes() {
+        let bytes = record_bytes(1, 6, &[10, 0, 0, 1, 0, 0]);
+        let mut buffer = BytePacketBuffer::from_bytes(&bytes);
+
+        assert!(DnsRecord::read(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn rejects_rdata_past_the_end_of_the_packet() {
+        let bytes = record_bytes(99, 200, &[1, 2, 3]);
+        let mut buffer = BytePacketBuffer::from_bytes(&bytes);
+
+        assert!(DnsRecord::read(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn generic_rdata_round_trips() {
+        let data = vec![0x0a, 0x00, 0x00, 0x01, 0xff];
+        let text = DnsRecord::to_generic_rdata(&data);
+        assert_eq!(text, "\\# 5 0a000001ff");
+        assert_eq!(DnsRecord::from_generic_rdata(&text).unwrap(), data);
+
+        // Hex may be split up by whitespace
+        assert_eq!(DnsRecord::from_generic_rdata("\\# 5 0a00 0001 ff").unwrap(), data);
+    }
+
+    #[test]
+    fn generic_rdata_may_be_empty() {
+        assert_eq!(DnsRecord::to_generic_rdata(&[]), "\\# 0");
+        assert_eq!(DnsRecord::from_generic_rdata("\\# 0").unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn generic_rdata_rejects_malformed_text() {
+        // Odd number of hex digits
+        assert!(DnsRecord::from_generic_rdata("\\# 2 abc").is_err());
+        assert!(DnsRecord::from_generic_rdata("\\# 1 abc").is_err());
+        // Length does not match the data
+        assert!(DnsRecord::from_generic_rdata("\\# 3 0a0000").is_ok());
+        assert!(DnsRecord::from_generic_rdata("\\# 4 0a0000").is_err());
+        assert!(DnsRecord::from_generic_rdata("\\# 0 0a").is_err());
+        // Missing prefix, length or valid hex
+        assert!(DnsRecord::from_generic_rdata("4 0a000001").is_err());
+        assert!(DnsRecord::from_generic_rdata("\\#").is_err());
+        assert!(DnsRecord::from_generic_rdata("\\# 1 zz").is_err());
+    }
+
+    #[test]
+    fn unknown_record_keeps_class_and_rdata() {
+        let record = DnsRecord::UNKNOWN {
+            domain: "a".to_string(),
+            qtype: 99,
+            class: 3,
+            data: vec![1, 2, 3],
+            ttl: 3600,
+        };
+        let mut buffer = BytePacketBuffer::new();
+        record.write(&mut buffer).unwrap();
+
+        let mut buffer = BytePacketBuffer::from_bytes(&buffer.buf[..buffer.pos()]);
+        assert_eq!(DnsRecord::read(&mut buffer).unwrap(), record);
+    }
+
+    #[test]
+    fn compressed_rdata_has_its_length() {
+        let mut buffer = BytePacketBuffer::with_max_size(512);
+        let ns = DnsRecord::NS {
+            domain: "example.com".to_string(),
+            host: "ns1.example.com".to_string(),
+            ttl: 3600,
+        };
+        let mx = DnsRecord::MX {
+            domain: "example.com".to_string(),
+            priority: 10,
+            host: "mail.example.com".to_string(),
+            ttl: 3600,
+        };
+        ns.write(&mut buffer).unwrap();
+        let mx_pos = buffer.pos();
+        mx.write(&mut buffer).unwrap();
+
+        // Owner, type, class and ttl come before RDLENGTH, the hosts
+        // are a label and a pointer to the first owner
+        assert_eq!(&buffer.buf[21..mx_pos], b"\x00\x06\x03ns1\xC0\x00");
+        assert_eq!(&buffer.buf[mx_pos + 10..mx_pos + 12], &[0, 9]);
+        assert_eq!(buffer.pos(), mx_pos + 12 + 9);
+
+        let mut buffer = BytePacketBuffer::from_bytes(&buffer.buf[..buffer.pos()]);
+        assert_eq!(DnsRecord::read(&mut buffer).unwrap(), ns);
+        assert_eq!(DnsRecord::read(&mut buffer).unwrap(), mx);
+        assert_eq!(buffer.pos(), buffer.buf.len());
+    }
+
+    fn round_trip(record: &DnsRecord) -> DnsRecord {
+        let mut buffer = BytePacketBuffer::with_max_size(65535);
+        record.write(&mut buffer).unwrap();
+
+        let mut buffer = BytePacketBuffer::from_bytes(&buffer.buf[..buffer.pos()]);
+        let read = DnsRecord::read(&mut buffer).unwrap();
+        assert_eq!(buffer.pos(), buffer.buf.len());
+        read
+    }
+
+    #[allow(clippy::redundant_field_names)]
+    fn nsec(types: Vec<QueryType>) -> DnsRecord {
+        DnsRecord::NSEC {
+            domain: "Host.Example.COM".to_string(),
+            next_domain: "Next.Example.COM".to_string(),
+            types: types,
+            ttl: 300,
+        }
+    }
+
+    #[allow(clippy::redundant_field_names)]
+    fn nsec3(types: Vec<QueryType>) -> DnsRecord {
+        DnsRecord::NSEC3 {
+            domain: "2T7B4G4VSA5SMI47K61MV5BV1A22BOJR.example.com".to_string(),
+            hash_algorithm: 1,
+            flags: 1,
+            iterations: 10,
+            salt: vec![0xAA, 0xBB],
+            next_hashed: (0..20).collect(),
+            types: types,
+            ttl: 300,
+        }
+    }
+
+    #[test]
+    fn dnssec_records_round_trip() {
+        let records = vec![
+            DnsRecord::DS {
+                domain: "Example.com".to_string(),
+                key_tag: 20326,
+                algorithm: 8,
+                digest_type: 2,
+                digest: (0..32).collect(),
+                ttl: 86400,
+            },
+            DnsRecord::DNSKEY {
+                domain: "Example.com".to_string(),
+                flags: 257,
+                protocol: 3,
+                algorithm: 13,
+                public_key: (0..64).collect(),
+                ttl: 3600,
+            },
+            DnsRecord::RRSIG {
+                domain: "WWW.Example.com".to_string(),
+                type_covered: QueryType::A,
+                algorithm: 13,
+                labels: 3,
+                original_ttl: 300,
+                expiration: 1900000000,
+                inception: 1700000000,
+                key_tag: 12345,
+                signer: "Example.com".to_string(),
+                signature: (0..64).collect(),
+                ttl: 300,
+            },
+            nsec(vec![QueryType::A, QueryType::RRSIG, QueryType::NSEC]),
+            nsec3(vec![QueryType::A, QueryType::AAAA, QueryType::RRSIG]),
+            DnsRecord::NSEC3PARAM {
+                domain: "example.com".to_string(),
+                hash_algorithm: 1,
+                flags: 0,
+                iterations: 0,
+                salt: Vec::new(),
+                ttl: 0,
+            },
+        ];
+
+        for record in &records {
+            assert_eq!(&round_trip(record), record);
+        }
+    }
+
+    #[test]
+    fn type_bitmaps_may_be_empty() {
+        let record = nsec(Vec::new());
+        assert_eq!(round_trip(&record), record);
+
+        let record = nsec3(Vec::new());
+        assert_eq!(round_trip(&record), record);
+    }
+
+    #[test]
+    fn type_bitmaps_span_every_window() {
+        // Every type of the first two windows and the last of all
+        let types = (1..=511).chain([1000, 32768, 65535].iter().cloned())
+            .map(QueryType::from_num)
+            .collect::<Vec<QueryType>>();
+
+        let record = nsec(types.clone());
+        assert_eq!(round_trip(&record), record);
+
+        let record = nsec3(types);
+        assert_eq!(round_trip(&record), record);
+    }
+
+    #[test]
+    fn names_keep_their_case() {
+        let mut buffer = BytePacketBuffer::new();
+        buffer.write_qname("WWW.Example.COM").unwrap();
+
+        let mut buffer = BytePacketBuffer::from_bytes(&buffer.buf[..buffer.pos()]);
+        let mut name = String::new();
+        buffer.read_qname(&mut name).unwrap();
+        assert_eq!(name, "WWW.Example.COM");
+    }
+
+    #[test]
+    fn canonical_form_keeps_the_case_of_the_nsec_next_name() {
+        let mut buffer = BytePacketBuffer::new();
+        buffer.set_canonical(true);
+        nsec(vec![QueryType::A]).write(&mut buffer).unwrap();
+
+        let data = &buffer.buf[..buffer.pos()];
+        let owner = b"\x04host\x07example\x03com\x00";
+        let next = b"\x04Next\x07Example\x03COM\x00";
+        assert_eq!(&data[..owner.len()], &owner[..]);
+        assert!(data.windows(next.len()).any(|x| x == &next[..]));
+    }
+
+    fn dname(domain: &str, host: &str) -> DnsRecord {
+        DnsRecord::DNAME {
+            domain: domain.to_string(),
+            host: host.to_string(),
+            ttl: 300,
+        }
+    }
+
+    #[test]
+    fn dname_substitutes_names_below_its_owner() {
+        let record = dname("Example.com", "example.net");
+        assert_eq!(record.dname_substitute("www.EXAMPLE.com"), Some("www.example.net".to_string()));
+        assert_eq!(record.dname_substitute("a.b.example.com."), Some("a.b.example.net".to_string()));
+        assert_eq!(record.dname_substitute("example.com"), None);
+        assert_eq!(record.dname_substitute("www.example.org"), None);
+    }
+
+    #[test]
+    fn dname_substitute_survives_non_ascii_names() {
+        // Both of these change their length in bytes when lowercased
+        let record = dname("\u{212A}.com", "example.net");
+        assert_eq!(record.dname_substitute("a\u{e9}.k.com"), None);
+
+        let record = dname("k.com", "example.net");
+        assert_eq!(record.dname_substitute("\u{e9}.\u{212A}.com"), None);
+        assert_eq!(record.dname_substitute("\u{e9}.k.com"), Some("\u{e9}.example.net".to_string()));
+    }
+}
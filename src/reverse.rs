@@ -0,0 +1,129 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+const IPV4_SUFFIX: &str = ".in-addr.arpa";
+const IPV6_SUFFIX: &str = ".ip6.arpa";
+
+// 1.2.3.4 -> 4.3.2.1.in-addr.arpa
+pub fn ipv4_to_arpa(addr: &Ipv4Addr) -> String {
+    let octets = addr.octets();
+    format!("{}.{}.{}.{}{}", octets[3], octets[2], octets[1], octets[0], IPV4_SUFFIX)
+}
+
+// Every nibble becomes its own label, least significant first,
+// e.g. 2001:db8::1 -> 1.0.0.0 ... 8.b.d.0.1.0.0.2.ip6.arpa
+pub fn ipv6_to_arpa(addr: &Ipv6Addr) -> String {
+    let mut name = String::new();
+    for octet in addr.octets().iter().rev() {
+        name.push_str(&format!("{:x}.{:x}.", octet & 0x0F, octet >> 4));
+    }
+    name.pop();
+    name.push_str(IPV6_SUFFIX);
+    name
+}
+
+pub fn ip_to_arpa(addr: &IpAddr) -> String {
+    match *addr {
+        IpAddr::V4(ref v4) => ipv4_to_arpa(v4),
+        IpAddr::V6(ref v6) => ipv6_to_arpa(v6),
+    }
+}
+
+// Turns a reverse name back into the address it stands for, only full
+// addresses are accepted (not partial zones such as 2.1.in-addr.arpa)
+pub fn arpa_to_ip(name: &str) -> Option<IpAddr> {
+    let name = name.trim_end_matches('.').to_lowercase();
+
+    if name.ends_with(IPV4_SUFFIX) {
+        let labels = name[..name.len() - IPV4_SUFFIX.len()]
+            .split('.')
+            .collect::<Vec<&str>>();
+        if labels.len() != 4 {
+            return None; }
+
+        let mut octets = [0u8; 4];
+        for (i, label) in labels.iter().rev().enumerate() {
+            // Plain decimal only, no signs or leading zeros
+            if label.is_empty() || !label.bytes().all(|b| b.is_ascii_digit()) ||
+                (label.len() > 1 && label.starts_with('0')) {
+                return None; }
+
+            octets[i] = label.parse::<u8>().ok()?;
+        }
+
+        return Some(IpAddr::V4(Ipv4Addr::from(octets)));
+    }
+
+    if name.ends_with(IPV6_SUFFIX) {
+        let labels = name[..name.len() - IPV6_SUFFIX.len()]
+            .split('.')
+            .collect::<Vec<&str>>();
+        if labels.len() != 32 {
+            return None; }
+
+        let mut octets = [0u8; 16];
+        for (i, label) in labels.iter().rev().enumerate() {
+            if label.len() != 1 || !label.as_bytes()[0].is_ascii_hexdigit() {
+                return None; }
+
+            let nibble = u8::from_str_radix(label, 16).ok()?;
+            // Labels come most significant nibble first once reversed
+            if i % 2 == 0 {
+                octets[i / 2] |= nibble << 4;
+            } else {
+                octets[i / 2] |= nibble;
+            }
+        }
+
+        return Some(IpAddr::V6(Ipv6Addr::from(octets)));
+    }
+
+    None
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipv4_round_trips() {
+        let addr = Ipv4Addr::new(192, 0, 2, 10);
+        assert_eq!(ipv4_to_arpa(&addr), "10.2.0.192.in-addr.arpa");
+        assert_eq!(arpa_to_ip("10.2.0.192.in-addr.arpa"), Some(IpAddr::V4(addr)));
+        assert_eq!(arpa_to_ip("10.2.0.192.IN-ADDR.ARPA."), Some(IpAddr::V4(addr)));
+        assert_eq!(arpa_to_ip("0.0.0.0.in-addr.arpa"), Some(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0))));
+    }
+
+    #[test]
+    fn ipv6_round_trips() {
+        let addr: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let name = "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa";
+        assert_eq!(ipv6_to_arpa(&addr), name);
+        assert_eq!(arpa_to_ip(name), Some(IpAddr::V6(addr)));
+        assert_eq!(arpa_to_ip(&name.to_uppercase()), Some(IpAddr::V6(addr)));
+        assert_eq!(ip_to_arpa(&IpAddr::V6(addr)), name);
+    }
+
+    #[test]
+    fn rejects_malformed_ipv4_labels() {
+        assert_eq!(arpa_to_ip("+1.2.0.192.in-addr.arpa"), None);
+        assert_eq!(arpa_to_ip("01.2.0.192.in-addr.arpa"), None);
+        assert_eq!(arpa_to_ip("256.2.0.192.in-addr.arpa"), None);
+        assert_eq!(arpa_to_ip("-1.2.0.192.in-addr.arpa"), None);
+        assert_eq!(arpa_to_ip(".2.0.192.in-addr.arpa"), None);
+        // Partial zones and too many labels
+        assert_eq!(arpa_to_ip("2.0.192.in-addr.arpa"), None);
+        assert_eq!(arpa_to_ip("1.10.2.0.192.in-addr.arpa"), None);
+        assert_eq!(arpa_to_ip("in-addr.arpa"), None);
+    }
+
+    #[test]
+    fn rejects_malformed_ipv6_labels() {
+        let name = "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa";
+        assert_eq!(arpa_to_ip(&name.replacen('1', "+", 1)), None);
+        assert_eq!(arpa_to_ip(&name.replacen('1', "01", 1)), None);
+        assert_eq!(arpa_to_ip(&name.replacen('1', "g", 1)), None);
+        assert_eq!(arpa_to_ip(&name[2..]), None);
+        assert_eq!(arpa_to_ip("example.com"), None);
+    }
+}
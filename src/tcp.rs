@@ -0,0 +1,134 @@
+use super::BytePacketBuffer;
+use std::cmp;
+use std::io::{Error, Read, Write};
+use std::io::ErrorKind::TimedOut;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+// Connection whose reads and writes give up after idle without progress,
+// and altogether once the deadline has passed, however slowly a client
+// trickles its data in or takes it out
+pub struct TimedStream {
+    stream: TcpStream,
+    idle: Duration,
+    deadline: Instant,
+}
+
+impl TimedStream {
+    pub fn new(stream: TcpStream, idle: Duration, limit: Duration) -> TimedStream {
+        TimedStream {
+            stream,
+            idle,
+            deadline: Instant::now() + limit,
+        }
+    }
+
+    fn timeout(&self) -> Result<Duration, Error> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(Error::new(TimedOut, "Connection has been open for too long")) }
+
+        Ok(cmp::min(left, self.idle))
+    }
+}
+
+impl Read for TimedStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.stream.set_read_timeout(Some(self.timeout()?))?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for TimedStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.stream.set_write_timeout(Some(self.timeout()?))?;
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.stream.flush()
+    }
+}
+
+// Over TCP every message is prefixed by its length as 2 bytes (RFC 1035 4.2.2)
+pub fn read_message<T: Read>(stream: &mut T) -> Result<BytePacketBuffer, Error> {
+    let mut len_buf = [0; 2];
+    stream.read_exact(&mut len_buf)?;
+    let len = ((len_buf[0] as usize) << 8) | (len_buf[1] as usize);
+
+    let mut data = vec![0; len];
+    stream.read_exact(&mut data)?;
+
+    Ok(BytePacketBuffer::from_bytes(&data))
+}
+
+pub fn write_message<T: Write>(stream: &mut T, data: &[u8]) -> Result<(), Error> {
+    let len = data.len() as u16;
+
+    // Written in one go, so the prefix is not sent in a segment of its own
+    let mut framed = Vec::with_capacity(data.len() + 2);
+    framed.push((len >> 8) as u8);
+    framed.push((len & 0xFF) as u8);
+    framed.extend_from_slice(data);
+
+    stream.write_all(&framed)?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn connected() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn reads_time_out_when_idle() {
+        let (_client, server) = connected();
+        let mut stream = TimedStream::new(server, Duration::from_millis(100), Duration::from_secs(10));
+
+        let start = Instant::now();
+        assert!(read_message(&mut stream).is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn trickling_clients_hit_the_deadline() {
+        let (mut client, server) = connected();
+        let trickle = thread::spawn(move || {
+            // Never idle for long, but the message is never finished either
+            for _ in 0..30 {
+                if client.write_all(&[0xFF]).is_err() {
+                    break; }
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+
+        let mut stream = TimedStream::new(server, Duration::from_millis(200), Duration::from_millis(400));
+        let start = Instant::now();
+        assert!(read_message(&mut stream).is_err());
+        // Kept alive past the idle timeout, but cut off at the deadline
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert!(start.elapsed() < Duration::from_millis(1000));
+
+        drop(stream);
+        trickle.join().unwrap();
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let (client, server) = connected();
+        let mut client = TimedStream::new(client, Duration::from_secs(1), Duration::from_secs(10));
+        let mut server = TimedStream::new(server, Duration::from_secs(1), Duration::from_secs(10));
+
+        write_message(&mut client, &[1, 2, 3]).unwrap();
+        let buffer = read_message(&mut server).unwrap();
+        assert_eq!(buffer.buf, vec![1, 2, 3]);
+    }
+}
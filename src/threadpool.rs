@@ -0,0 +1,90 @@
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::panic::{self, AssertUnwindSafe};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// Fixed number of worker threads taking jobs off a shared queue, so a
+// slow lookup only ties up one worker instead of the whole server.
+// The queue is bounded, a flood of jobs is turned away rather than
+// piling up without limit
+pub struct ThreadPool {
+    sender: Mutex<SyncSender<Job>>,
+}
+
+impl ThreadPool {
+    pub fn new(size: usize, queue_len: usize) -> ThreadPool {
+        let (sender, receiver) = sync_channel::<Job>(queue_len);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for id in 0..size {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("worker-{}", id))
+                .spawn(move || ThreadPool::work(receiver))
+                .unwrap();
+        }
+
+        ThreadPool {
+            sender: Mutex::new(sender),
+        }
+    }
+
+    fn work(receiver: Arc<Mutex<Receiver<Job>>>) {
+        loop {
+            // Lock is only held while waiting, not while running the job
+            let job = match receiver.lock().unwrap().recv() {
+                Ok(x) => x,
+                Err(_) => return,
+            };
+
+            // A panicking job should not take its worker down with it
+            if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                println!("Worker job panicked");
+            }
+        }
+    }
+
+    // Queues a job, it runs as soon as a worker is free. Returns false
+    // when the queue is full and the job was dropped
+    pub fn execute<F>(&self, job: F) -> bool where F: FnOnce() + Send + 'static {
+        match self.sender.lock().unwrap().try_send(Box::new(job)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => false,
+            Err(TrySendError::Disconnected(_)) => {
+                println!("Failed to queue job, no workers left");
+                false
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn drops_jobs_once_the_queue_is_full() {
+        let pool = ThreadPool::new(1, 1);
+        let (started_tx, started_rx) = channel();
+        let (release_tx, release_rx) = channel::<()>();
+
+        // Keeps the only worker busy until released
+        assert!(pool.execute(move || {
+            started_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+        }));
+        started_rx.recv().unwrap();
+
+        let (done_tx, done_rx) = channel();
+        let queued_tx = done_tx.clone();
+        assert!(pool.execute(move || queued_tx.send("queued").unwrap()));
+        assert!(!pool.execute(move || done_tx.send("dropped").unwrap()));
+
+        release_tx.send(()).unwrap();
+        assert_eq!(done_rx.recv().unwrap(), "queued");
+        assert!(done_rx.recv().is_err());
+    }
+}
@@ -0,0 +1,217 @@
+# Writes dnssec.txt, the signed zones the validator tests run against: the
+# trust anchor on the first line, then one packet per line as hex, keyed by
+# test case or by the "name|TYPE" the validator fetches. Keys are made anew
+# on every run, so all of the file changes when it is regenerated
+import struct, hashlib, os, base64
+from cryptography.hazmat.primitives.asymmetric import rsa, ec, ed25519, padding
+from cryptography.hazmat.primitives import hashes
+from cryptography.hazmat.primitives.asymmetric.utils import decode_dss_signature
+
+INCEPTION, EXPIRATION = 1700000000, 1900000000
+T = dict(A=1, NS=2, CNAME=5, SOA=6, TXT=16, AAAA=28, DNAME=39, DS=43, RRSIG=46, NSEC=47, DNSKEY=48, NSEC3=50)
+
+def name_wire(n):
+    n = n.strip('.').lower()
+    out = b''
+    if n:
+        for l in n.split('.'):
+            out += bytes([len(l)]) + l.encode()
+    return out + b'\0'
+
+def canon_labels(n):
+    return [l.lower().encode() for l in reversed([x for x in n.strip('.').split('.') if x])]
+
+def bitmap(types):
+    nums = sorted(set(T[t] for t in types))
+    out = b''
+    windows = {}
+    for n in nums:
+        windows.setdefault(n >> 8, []).append(n & 0xff)
+    for w, lows in sorted(windows.items()):
+        bits = bytearray(max(lows) // 8 + 1)
+        for l in lows:
+            bits[l // 8] |= 0x80 >> (l % 8)
+        out += bytes([w, len(bits)]) + bytes(bits)
+    return out
+
+class Key:
+    def __init__(self, zone, alg, flags):
+        self.zone, self.alg, self.flags = zone, alg, flags
+        if alg == 8:
+            self.priv = rsa.generate_private_key(65537, 2048)
+            nums = self.priv.public_key().public_numbers()
+            e = nums.e.to_bytes((nums.e.bit_length() + 7) // 8, 'big')
+            n = nums.n.to_bytes(256, 'big')
+            self.pub = bytes([len(e)]) + e + n
+        elif alg in (13, 14):
+            curve, size = (ec.SECP256R1(), 32) if alg == 13 else (ec.SECP384R1(), 48)
+            self.priv = ec.generate_private_key(curve)
+            nums = self.priv.public_key().public_numbers()
+            self.pub = nums.x.to_bytes(size, 'big') + nums.y.to_bytes(size, 'big')
+            self.size = size
+        elif alg == 15:
+            self.priv = ed25519.Ed25519PrivateKey.generate()
+            from cryptography.hazmat.primitives import serialization
+            self.pub = self.priv.public_key().public_bytes(serialization.Encoding.Raw, serialization.PublicFormat.Raw)
+        self.rdata = struct.pack('!HBB', flags, 3, alg) + self.pub
+        self.tag = keytag(self.rdata)
+
+    def sign(self, data):
+        if self.alg == 8:
+            return self.priv.sign(data, padding.PKCS1v15(), hashes.SHA256())
+        if self.alg in (13, 14):
+            h = hashes.SHA256() if self.alg == 13 else hashes.SHA384()
+            r, s = decode_dss_signature(self.priv.sign(data, ec.ECDSA(h)))
+            return r.to_bytes(self.size, 'big') + s.to_bytes(self.size, 'big')
+        return self.priv.sign(data)
+
+    def record(self, ttl=3600):
+        return (self.zone, 'DNSKEY', ttl, self.rdata)
+
+    def ds(self, ttl=3600):
+        d = hashlib.sha256(name_wire(self.zone) + self.rdata).digest()
+        return (self.zone, 'DS', ttl, struct.pack('!HBB', self.tag, self.alg, 2) + d)
+
+def keytag(rdata):
+    ac = 0
+    for i, b in enumerate(rdata):
+        ac += b if i & 1 else b << 8
+    ac += (ac >> 16) & 0xffff
+    return ac & 0xffff
+
+def rrsig(rrset, key, inception=INCEPTION, expiration=EXPIRATION, labels=None, owner_override=None):
+    owner, typ, ttl, _ = rrset[0]
+    if labels is None:
+        labels = len([l for l in owner.strip('.').split('.') if l and l != '*'])
+    head = struct.pack('!HBBIIIH', T[typ], key.alg, labels, ttl, expiration, inception, key.tag) + name_wire(key.zone)
+    signed_owner = owner_override if owner_override is not None else owner
+    rrs = sorted(set(r[3] for r in rrset))
+    data = head
+    for rd in rrs:
+        data += name_wire(signed_owner) + struct.pack('!HHIH', T[typ], 1, ttl, len(rd)) + rd
+    return (owner, 'RRSIG', ttl, head + key.sign(data))
+
+def rr_wire(r):
+    owner, typ, ttl, rd = r
+    return name_wire(owner) + struct.pack('!HHIH', T[typ], 1, ttl, len(rd)) + rd
+
+def packet(qname, qtype, answers=(), authorities=(), rcode=0, aa=True):
+    flags = 0x8000 | (0x0400 if aa else 0) | rcode
+    hdr = struct.pack('!HHHHHH', 1, flags, 1, len(answers), len(authorities), 0)
+    q = name_wire(qname) + struct.pack('!HH', T[qtype], 1)
+    return hdr + q + b''.join(rr_wire(r) for r in answers) + b''.join(rr_wire(r) for r in authorities)
+
+def soa(zone):
+    rd = name_wire('ns.' + zone if zone else 'ns') + name_wire('admin') + struct.pack('!IIIII', 1, 3600, 600, 86400, 300)
+    return (zone, 'SOA', 3600, rd)
+
+def nsec(owner, nxt, types, ttl=300):
+    return (owner, 'NSEC', ttl, name_wire(nxt) + bitmap(types))
+
+def b32hex(b):
+    return base64.b32hexencode(b).decode().strip('=').lower()
+
+def nsec3hash(name, salt=b'', it=0):
+    h = hashlib.sha1(name_wire(name) + salt).digest()
+    for _ in range(it):
+        h = hashlib.sha1(h + salt).digest()
+    return h
+
+fixtures = {}
+def add(key, data):
+    fixtures[key] = data.hex()
+
+# Root: RSA KSK + Ed25519 ZSK
+root_ksk = Key('', 8, 257)
+root_zsk = Key('', 15, 256)
+root_keys = [root_ksk.record(), root_zsk.record()]
+add('|DNSKEY', packet('', 'DNSKEY', root_keys + [rrsig(root_keys, root_ksk)]))
+anchor = root_ksk.ds()
+anchor_text = '. 86400 IN DS %d 8 2 %s ; root KSK' % (root_ksk.tag, anchor[3][4:].hex().upper())
+
+# test: P-256 CSK
+test_key = Key('test', 13, 257)
+test_ds = [test_key.ds()]
+add('test|DS', packet('test', 'DS', test_ds + [rrsig(test_ds, root_zsk)]))
+add('test|DNSKEY', packet('test', 'DNSKEY', [test_key.record(), rrsig([test_key.record()], test_key)]))
+
+a = [('www.test', 'A', 300, bytes([1, 2, 3, 4]))]
+add('good', packet('www.test', 'A', a + [rrsig(a, test_key)]))
+forged = [('www.test', 'A', 300, bytes([6, 6, 6, 6]))]
+add('forged', packet('www.test', 'A', forged + [rrsig(a, test_key)]))
+add('stripped', packet('www.test', 'A', a))
+add('expired', packet('www.test', 'A', a + [rrsig(a, test_key, inception=1600000000, expiration=1750000000)]))
+
+# NXDOMAIN and NODATA in test with NSEC
+n1 = [nsec('test', 'www.test', ['SOA', 'NS', 'DNSKEY', 'RRSIG', 'NSEC'])]
+add('nxdomain', packet('nope.test', 'A', [], [soa('test'), rrsig([soa('test')], test_key)] + n1 + [rrsig(n1, test_key)], rcode=3))
+add('nxdomain_nosig', packet('nope.test', 'A', [], [soa('test')], rcode=3))
+n2 = [nsec('www.test', 'test', ['A', 'RRSIG', 'NSEC'])]
+add('nodata', packet('www.test', 'AAAA', [], [soa('test'), rrsig([soa('test')], test_key)] + n2 + [rrsig(n2, test_key)]))
+add('nodata_lie', packet('www.test', 'A', [], [soa('test'), rrsig([soa('test')], test_key)] + n2 + [rrsig(n2, test_key)]))
+
+# Wildcard expansion: *.wild.test A, asked as foo.wild.test
+wa = [('foo.wild.test', 'A', 300, bytes([9, 9, 9, 9]))]
+wsig = rrsig(wa, test_key, labels=2, owner_override='*.wild.test')
+add('wildcard', packet('foo.wild.test', 'A', wa + [wsig], n1 + [rrsig(n1, test_key)]))
+add('wildcard_noproof', packet('foo.wild.test', 'A', wa + [wsig]))
+
+# p384.test: P-384 CSK, DS signed by test
+p384_key = Key('p384.test', 14, 257)
+p384_ds = [p384_key.ds()]
+add('p384.test|DS', packet('p384.test', 'DS', p384_ds + [rrsig(p384_ds, test_key)]))
+add('p384.test|DNSKEY', packet('p384.test', 'DNSKEY', [p384_key.record(), rrsig([p384_key.record()], p384_key)]))
+txt = [('a.p384.test', 'TXT', 300, b'\x05hello')]
+add('p384', packet('a.p384.test', 'TXT', txt + [rrsig(txt, p384_key)]))
+
+# insecure: root proves there is no DS with an NSEC
+ni = [nsec('insecure', 'zz', ['NS', 'RRSIG', 'NSEC'])]
+add('insecure|DS', packet('insecure', 'DS', [], [soa(''), rrsig([soa('')], root_zsk)] + ni + [rrsig(ni, root_zsk)]))
+add('insecure', packet('host.insecure', 'A', [('host.insecure', 'A', 300, bytes([5, 5, 5, 5]))]))
+
+# nsec3: P-256 CSK with an NSEC3 chain with opt-out
+n3_key = Key('nsec3', 13, 257)
+n3_ds = [n3_key.ds()]
+add('nsec3|DS', packet('nsec3', 'DS', n3_ds + [rrsig(n3_ds, root_zsk)]))
+add('nsec3|DNSKEY', packet('nsec3', 'DNSKEY', [n3_key.record(), rrsig([n3_key.record()], n3_key)]))
+names = {'nsec3': ['SOA', 'NS', 'DNSKEY', 'RRSIG', 'NSEC3'], 'www.nsec3': ['A', 'RRSIG'], 'x.nsec3': ['A', 'RRSIG']}
+hashed = sorted((nsec3hash(n), t) for n, t in names.items())
+chain = []
+for i, (h, types) in enumerate(hashed):
+    nxt = hashed[(i + 1) % len(hashed)][0]
+    rd = struct.pack('!BBHB', 1, 1, 0, 0) + bytes([len(nxt)]) + nxt + bitmap(types)
+    chain.append(('%s.nsec3' % b32hex(h), 'NSEC3', 300, rd))
+chain_sigs = [rrsig([r], n3_key) for r in chain]
+add('nsec3_nxdomain', packet('nope.nsec3', 'A', [], [soa('nsec3'), rrsig([soa('nsec3')], n3_key)] + chain + chain_sigs, rcode=3))
+add('unsigned.nsec3|DS', packet('unsigned.nsec3', 'DS', [], [soa('nsec3'), rrsig([soa('nsec3')], n3_key)] + chain + chain_sigs))
+add('unsigned', packet('host.unsigned.nsec3', 'A', [('host.unsigned.nsec3', 'A', 300, bytes([7, 7, 7, 7]))]))
+
+# Signer downgrade: www.test is no zone, so test proves it has no DS,
+# which must not let a key of its own sign data answered by test
+rogue_key = Key('www.test', 13, 257)
+add('www.test|DS', packet('www.test', 'DS', [], [soa('test'), rrsig([soa('test')], test_key)] + n2 + [rrsig(n2, test_key)]))
+add('www.test|DNSKEY', packet('www.test', 'DNSKEY', [rogue_key.record(), rrsig([rogue_key.record()], rogue_key)]))
+add('downgrade', packet('www.test', 'A', forged + [rrsig(forged, rogue_key)]))
+
+# A forged referral to gone.test, which test proves does not exist
+add('gone.test|DS', packet('gone.test', 'DS', [], [soa('test'), rrsig([soa('test')], test_key)] + n1 + [rrsig(n1, test_key)], rcode=3))
+add('gone', packet('host.gone.test', 'A', [('host.gone.test', 'A', 300, bytes([8, 8, 8, 8]))]))
+
+# dn.test redirects to elsewhere, the CNAME made from it is not signed
+dn = [('dn.test', 'DNAME', 300, name_wire('elsewhere'))]
+synthesized = ('a.dn.test', 'CNAME', 300, name_wire('a.elsewhere'))
+add('dname', packet('a.dn.test', 'A', dn + [rrsig(dn, test_key), synthesized]))
+add('dname_forged', packet('a.dn.test', 'A', dn + [rrsig(dn, test_key), ('a.dn.test', 'CNAME', 300, name_wire('evil'))]))
+
+# Answers with other rcodes say nothing, not even that a zone is unsigned
+add('notimp', packet('www.test', 'A', [], rcode=4))
+add('spoofed|DS', packet('spoofed', 'DS', [], rcode=4))
+add('spoofed', packet('host.spoofed', 'A', [('host.spoofed', 'A', 300, bytes([6, 6, 6, 6]))]))
+
+# NODATA in nsec3: www.nsec3 only has an A record
+add('nsec3_nodata', packet('www.nsec3', 'AAAA', [], [soa('nsec3'), rrsig([soa('nsec3')], n3_key)] + chain + chain_sigs))
+
+with open(os.path.join(os.path.dirname(os.path.abspath(__file__)), 'dnssec.txt'), 'w') as f:
+    f.write(anchor_text + '\n')
+    for k in sorted(fixtures):
+        f.write('%s %s\n' % (k, fixtures[k]))